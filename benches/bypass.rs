@@ -0,0 +1,129 @@
+//! How much the player saves by copying frames that are already in the device's format straight
+//! into the ring buffer, rather than running them through an identity resampler.
+//!
+//! ```text
+//! cargo bench --bench bypass
+//! ```
+
+extern crate ffmpeg_next as ffmpeg;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use ffmpeg::format::{sample::Type as SampleType, Sample};
+use ffmpeg::software::resampling::context::Context as ResamplingContext;
+use ffmpeg::{frame, ChannelLayout};
+use ffmpeg_cpal_play_audio::{interleave, packed, planar, sample_buffer, SampleProducer};
+
+const RATE: u32 = 48000;
+// Samples per channel in each frame, as AAC and Opus decoders hand them out (give or take)
+const FRAME_SIZE: usize = 1024;
+// About three and a half minutes of audio, which is one song
+const FRAMES: usize = 10_000;
+// Each case runs this many times and the fastest run counts, to keep noise from other processes out
+const ROUNDS: usize = 5;
+
+fn main() {
+    ffmpeg::init().unwrap();
+
+    let packed_frame = test_frame(Sample::F32(SampleType::Packed));
+    let planar_frame = test_frame(Sample::F32(SampleType::Planar));
+
+    let mut resampler = identity_resampler(&packed_frame);
+    let packed_resampled = time(&packed_frame, |frame, producer| {
+        let mut resampled = frame::Audio::empty();
+        resampler.run(frame, &mut resampled).unwrap();
+        producer.push_iter(&mut packed::<f32>(&resampled).unwrap().iter().copied());
+    });
+    let packed_direct = time(&packed_frame, |frame, producer| {
+        producer.push_iter(&mut packed::<f32>(frame).unwrap().iter().copied());
+    });
+
+    let mut resampler = identity_resampler(&planar_frame);
+    let planar_resampled = time(&planar_frame, |frame, producer| {
+        let mut resampled = frame::Audio::empty();
+        resampler.run(frame, &mut resampled).unwrap();
+        producer.push_iter(&mut packed::<f32>(&resampled).unwrap().iter().copied());
+    });
+    let mut interleaved = Vec::new();
+    let planar_direct = time(&planar_frame, |frame, producer| {
+        interleave(&planar::<f32>(frame).unwrap(), &mut interleaved);
+        producer.push_iter(&mut interleaved.iter().copied());
+    });
+
+    println!("per minute of 48 kHz stereo f32 audio:");
+    report("packed, resampled", packed_resampled, None);
+    report("packed, direct", packed_direct, Some(packed_resampled));
+    report("planar, resampled", planar_resampled, None);
+    report("planar, interleaved", planar_direct, Some(planar_resampled));
+}
+
+// A stereo frame in `format` with a quiet tone in it
+fn test_frame(format: Sample) -> frame::Audio {
+    let mut frame = frame::Audio::new(format, FRAME_SIZE, ChannelLayout::STEREO);
+    frame.set_rate(RATE);
+
+    // A packed frame is one plane with both channels in it, so it's twice as long
+    let channels_per_plane = if frame.is_packed() { 2 } else { 1 };
+    let tone = |i: usize| ((i / channels_per_plane) as f32 * 0.05).sin() * 0.25;
+
+    // Only linesize[0] is set for audio, so data_mut(1) would be empty. plane_mut sizes planes by
+    // samples() instead, but that's one sample per channel, so the single packed plane uses data_mut(0).
+    if frame.is_packed() {
+        for (i, bytes) in frame.data_mut(0).chunks_exact_mut(4).take(FRAME_SIZE * 2).enumerate() {
+            bytes.copy_from_slice(&tone(i).to_ne_bytes());
+        }
+    } else {
+        for plane in 0..frame.planes() {
+            for (i, sample) in frame.plane_mut::<f32>(plane).iter_mut().enumerate() {
+                *sample = tone(i);
+            }
+        }
+    }
+    frame
+}
+
+// What the player would set up for a file that's already at the device's rate and layout
+fn identity_resampler(frame: &frame::Audio) -> ResamplingContext {
+    ResamplingContext::get(
+        frame.format(),
+        ChannelLayout::STEREO,
+        RATE,
+        Sample::F32(SampleType::Packed),
+        ChannelLayout::STEREO,
+        RATE,
+    )
+    .unwrap()
+}
+
+// Push `frame` into a ring buffer FRAMES times with `push`, returning the fastest of ROUNDS runs
+fn time(frame: &frame::Audio, mut push: impl FnMut(&frame::Audio, &mut SampleProducer<f32>)) -> Duration {
+    let (mut producer, mut consumer) = sample_buffer::<f32>(FRAME_SIZE * 2 * 4);
+    let mut fastest = Duration::MAX;
+
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            push(black_box(frame), &mut producer);
+            // Stand in for the output callback as cheaply as possible, so it's the pushing that's timed
+            producer.clear();
+            consumer.discard_stale();
+        }
+        fastest = fastest.min(start.elapsed());
+    }
+    fastest
+}
+
+fn report(name: &str, elapsed: Duration, baseline: Option<Duration>) {
+    let audio_seconds = (FRAMES * FRAME_SIZE) as f64 / f64::from(RATE);
+    let per_minute = elapsed.as_secs_f64() * 60.0 / audio_seconds;
+    match baseline {
+        Some(baseline) => println!(
+            "  {:<20} {:>8.3} ms  ({:.1}x faster)",
+            name,
+            per_minute * 1000.0,
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        ),
+        None => println!("  {:<20} {:>8.3} ms", name, per_minute * 1000.0),
+    }
+}
@@ -0,0 +1,212 @@
+use std::ffi::c_void;
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use ffmpeg::error::{EINVAL, EIO, ENOMEM, ENOSYS};
+use ffmpeg::ffi;
+use ffmpeg::format::context::Input;
+
+use crate::error::PlayerError;
+
+// How much ffmpeg reads from a reader at a time
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// whence values from stdio.h
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+const SEEK_END: c_int = 2;
+
+/// Anything that can be read from and seeked in, from another thread.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Somewhere to read a file from other than the filesystem: memory, a pipe, a socket...
+pub enum Reader {
+    /// Can seek, so every format works and so does seeking in the track.
+    Seekable(Box<dyn ReadSeek>),
+    /// Can only be read front to back, like a pipe. Seeking in the track is ignored, and formats
+    /// that need to seek to be read at all (like MP4 with its index at the end) won't open.
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Reader {
+    pub fn seekable<R: Read + Seek + Send + 'static>(reader: R) -> Reader {
+        Reader::Seekable(Box::new(reader))
+    }
+
+    pub fn stream<R: Read + Send + 'static>(reader: R) -> Reader {
+        Reader::Stream(Box::new(reader))
+    }
+
+    /// A whole file that's already in memory.
+    pub fn bytes(bytes: Vec<u8>) -> Reader {
+        Reader::seekable(Cursor::new(bytes))
+    }
+
+    /// Standard input, which can't seek.
+    pub fn stdin() -> Reader {
+        Reader::stream(io::stdin())
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        matches!(self, Reader::Seekable(_))
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Reader::Seekable(reader) => reader.seek(pos),
+            Reader::Stream(_) => Err(io::Error::other("can't seek in a stream")),
+        }
+    }
+
+    // How long the whole thing is, without moving
+    fn len(&mut self) -> io::Result<u64> {
+        let position = self.seek(SeekFrom::Current(0))?;
+        let end = self.seek(SeekFrom::End(0))?;
+        self.seek(SeekFrom::Start(position))?;
+        Ok(end)
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Reader::Seekable(reader) => reader.read(buf),
+            Reader::Stream(reader) => reader.read(buf),
+        }
+    }
+}
+
+// A reader a playlist entry can share between its clones. Whichever opens it first gets it,
+// since there's no way to read it again from the start afterwards.
+#[derive(Clone)]
+pub(crate) struct SharedReader(Arc<Mutex<Option<Reader>>>);
+
+impl SharedReader {
+    pub(crate) fn new(reader: Reader) -> SharedReader {
+        SharedReader(Arc::new(Mutex::new(Some(reader))))
+    }
+
+    pub(crate) fn take(&self) -> Result<Reader, PlayerError> {
+        self.0.lock().unwrap().take().ok_or_else(|| {
+            PlayerError::Io(io::Error::other("a reader can only be played once"))
+        })
+    }
+}
+
+impl fmt::Debug for SharedReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedReader")
+    }
+}
+
+impl PartialEq for SharedReader {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// ffmpeg's AVIOContext for a reader, which has to outlive the format context reading through it
+pub(crate) struct CustomIo {
+    avio: *mut ffi::AVIOContext,
+    // Boxed so it stays put while ffmpeg holds a pointer to it
+    _reader: Box<Reader>,
+}
+
+// The AVIOContext is only ever used by the thread that owns the Source it's in
+unsafe impl Send for CustomIo {}
+
+impl Drop for CustomIo {
+    fn drop(&mut self) {
+        unsafe {
+            // ffmpeg may have swapped the buffer for one of its own, so free whichever it has now
+            ffi::av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+            ffi::avio_context_free(&mut self.avio);
+        }
+    }
+}
+
+// Open `reader` the way format::input() opens a path. The CustomIo must be dropped after the Input.
+pub(crate) fn input_from_reader(reader: Reader) -> Result<(Input, CustomIo), PlayerError> {
+    let seekable = reader.is_seekable();
+    let mut reader = Box::new(reader);
+
+    unsafe {
+        let buffer = ffi::av_malloc(BUFFER_SIZE) as *mut u8;
+        if buffer.is_null() {
+            return Err(ffmpeg::Error::Other { errno: ENOMEM }.into());
+        }
+
+        let opaque = &mut *reader as *mut Reader as *mut c_void;
+        // Without a seek callback, ffmpeg knows not to try
+        let seek: Option<unsafe extern "C" fn(*mut c_void, i64, c_int) -> i64> =
+            if seekable { Some(seek_reader) } else { None };
+        let avio = ffi::avio_alloc_context(buffer, BUFFER_SIZE as c_int, 0, opaque, Some(read_reader), None, seek);
+        if avio.is_null() {
+            ffi::av_free(buffer as *mut c_void);
+            return Err(ffmpeg::Error::Other { errno: ENOMEM }.into());
+        }
+        let io = CustomIo { avio, _reader: reader };
+
+        let mut ctx = ffi::avformat_alloc_context();
+        if ctx.is_null() {
+            return Err(ffmpeg::Error::Other { errno: ENOMEM }.into());
+        }
+        (*ctx).pb = avio;
+
+        // avformat_open_input frees the context itself if it fails
+        match ffi::avformat_open_input(&mut ctx, ptr::null(), ptr::null_mut(), ptr::null_mut()) {
+            0 => {}
+            e => return Err(ffmpeg::Error::from(e).into()),
+        }
+        match ffi::avformat_find_stream_info(ctx, ptr::null_mut()) {
+            e if e < 0 => {
+                ffi::avformat_close_input(&mut ctx);
+                Err(ffmpeg::Error::from(e).into())
+            }
+            _ => Ok((Input::wrap(ctx), io)),
+        }
+    }
+}
+
+unsafe extern "C" fn read_reader(opaque: *mut c_void, buf: *mut u8, size: c_int) -> c_int {
+    let reader = &mut *(opaque as *mut Reader);
+    let buf = slice::from_raw_parts_mut(buf, size as usize);
+
+    // A panic can't unwind into ffmpeg, so it's just a read error
+    panic::catch_unwind(AssertUnwindSafe(|| loop {
+        match reader.read(buf) {
+            Ok(0) => return ffi::AVERROR_EOF,
+            Ok(read) => return read as c_int,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return ffi::AVERROR(EIO),
+        }
+    }))
+    .unwrap_or(ffi::AVERROR(EIO))
+}
+
+unsafe extern "C" fn seek_reader(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = &mut *(opaque as *mut Reader);
+
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        // AVSEEK_SIZE asks how big the file is rather than to move
+        if whence & ffi::AVSEEK_SIZE as c_int != 0 {
+            return reader.len().map(|len| len as i64).unwrap_or(i64::from(ffi::AVERROR(ENOSYS)));
+        }
+
+        let pos = match whence & !(ffi::AVSEEK_FORCE as c_int) {
+            SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CUR => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            _ => return i64::from(ffi::AVERROR(EINVAL)),
+        };
+        reader.seek(pos).map(|pos| pos as i64).unwrap_or(i64::from(ffi::AVERROR(EIO)))
+    }))
+    .unwrap_or(i64::from(ffi::AVERROR(EIO)))
+}
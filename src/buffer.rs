@@ -0,0 +1,304 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, Thread};
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+// Lets the output callback wake the decoder thread up without taking a lock. The decoder thread
+// parks itself when it's waiting for something, and the callback unparks it when things change.
+#[derive(Default)]
+pub(crate) struct Wakeup {
+    thread: OnceLock<Thread>,
+    waiting: AtomicBool,
+}
+
+impl Wakeup {
+    // Block the calling thread until `ready` returns true. Only one thread should ever wait.
+    pub(crate) fn wait_until(&self, mut ready: impl FnMut() -> bool) {
+        self.thread.get_or_init(thread::current);
+
+        while !ready() {
+            self.waiting.store(true, Ordering::SeqCst);
+
+            // Check again now that the other side can see we're waiting, or we could miss the wakeup
+            if ready() {
+                break;
+            }
+            thread::park();
+        }
+
+        self.waiting.store(false, Ordering::SeqCst);
+    }
+
+    // Wake the waiting thread, if there is one
+    pub(crate) fn notify(&self) {
+        if self.waiting.load(Ordering::SeqCst) {
+            self.wake();
+        }
+    }
+
+    // Wake the waiting thread even if it's not waiting yet, so it re-checks its condition next time
+    pub(crate) fn wake(&self) {
+        if let Some(thread) = self.thread.get() {
+            thread.unpark();
+        }
+    }
+}
+
+/// The decoder's end of the sample buffer.
+pub struct SampleProducer<T> {
+    inner: Producer<T>,
+    wakeup: Arc<Wakeup>,
+    // For a consumer that blocks waiting for samples (the output callback never does)
+    filled: Arc<Wakeup>,
+    // How many samples have ever been pushed
+    written: u64,
+    discard_before: Arc<AtomicU64>,
+}
+
+/// The output callback's end of the sample buffer.
+pub struct SampleConsumer<T> {
+    inner: Consumer<T>,
+    wakeup: Arc<Wakeup>,
+    filled: Arc<Wakeup>,
+    // How many samples have ever been popped (or discarded)
+    read: u64,
+    // The consumer throws away everything up to this many samples written. The producer
+    // moves it forward to clear the buffer, since it can't pop samples itself.
+    discard_before: Arc<AtomicU64>,
+}
+
+/// Create a ring buffer that holds `capacity` samples, where the producer can block until there's room.
+pub fn sample_buffer<T>(capacity: usize) -> (SampleProducer<T>, SampleConsumer<T>) {
+    let (producer, consumer) = RingBuffer::<T>::new(capacity).split();
+    let wakeup = Arc::new(Wakeup::default());
+    let filled = Arc::new(Wakeup::default());
+    let discard_before = Arc::new(AtomicU64::new(0));
+
+    (
+        SampleProducer {
+            inner: producer,
+            wakeup: wakeup.clone(),
+            filled: filled.clone(),
+            written: 0,
+            discard_before: discard_before.clone(),
+        },
+        SampleConsumer {
+            inner: consumer,
+            wakeup,
+            filled,
+            read: 0,
+            discard_before,
+        },
+    )
+}
+
+impl<T> SampleProducer<T> {
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// How many samples have been pushed since the buffer was created.
+    pub fn samples_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Block until at least `count` samples fit in the buffer. Returns false if `interrupted` returned true first.
+    pub fn wait_for_space(&self, count: usize, interrupted: impl Fn() -> bool) -> bool {
+        let count = count.min(self.inner.capacity());
+        self.wakeup.wait_until(|| self.inner.remaining() >= count || interrupted());
+        !interrupted()
+    }
+
+    pub fn push_iter<I: Iterator<Item = T>>(&mut self, samples: &mut I) -> usize {
+        let pushed = self.inner.push_iter(samples);
+        self.written += pushed as u64;
+        self.filled.notify();
+        pushed
+    }
+
+    /// Wake the consumer if it's blocked in `wait_for_samples`, so it re-checks whether it's been interrupted.
+    pub fn wake_consumer(&self) {
+        self.filled.wake();
+    }
+
+    /// Throw away everything currently in the buffer. The samples are actually dropped by the
+    /// consumer the next time it runs, but nothing pushed after this call is affected.
+    pub fn clear(&mut self) {
+        self.discard_before.store(self.written, Ordering::SeqCst);
+        self.wakeup.notify();
+    }
+
+    pub(crate) fn wakeup(&self) -> Arc<Wakeup> {
+        self.wakeup.clone()
+    }
+
+    // For whoever needs to interrupt the consumer's wait_for_samples, other than the producer
+    pub(crate) fn consumer_wakeup(&self) -> Arc<Wakeup> {
+        self.filled.clone()
+    }
+}
+
+impl<T> SampleConsumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let sample = self.inner.pop();
+        if sample.is_some() {
+            self.read += 1;
+        }
+        sample
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// How many samples are waiting to be popped.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// How many samples have been popped (or discarded) since the buffer was created.
+    pub fn samples_read(&self) -> u64 {
+        self.read
+    }
+
+    /// Drop any samples the producer has cleared. Call this before popping.
+    pub fn discard_stale(&mut self) {
+        let discard_before = self.discard_before.load(Ordering::SeqCst);
+        if self.read < discard_before {
+            self.read += self.inner.discard((discard_before - self.read) as usize) as u64;
+        }
+    }
+
+    /// Block until at least `count` samples are in the buffer. Returns false if `interrupted` returned true first.
+    ///
+    /// This is for consumers that run on their own thread rather than in an output callback.
+    pub fn wait_for_samples(&self, count: usize, interrupted: impl Fn() -> bool) -> bool {
+        let count = count.min(self.inner.capacity());
+        self.filled.wait_until(|| self.inner.len() >= count || interrupted());
+        self.inner.len() >= count
+    }
+
+    /// Let the producer know there's more room. Doesn't block, so it's safe to call from the output callback.
+    pub fn notify(&self) {
+        self.wakeup.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn pop_all<T>(consumer: &mut SampleConsumer<T>) -> Vec<T> {
+        std::iter::from_fn(|| consumer.pop()).collect()
+    }
+
+    #[test]
+    fn counts_what_goes_in_and_out() {
+        let (mut producer, mut consumer) = sample_buffer::<i32>(8);
+        assert_eq!(producer.push_iter(&mut (0..5)), 5);
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(consumer.pop(), Some(1));
+
+        assert_eq!(producer.samples_written(), 5);
+        assert_eq!(consumer.samples_read(), 2);
+        assert_eq!(consumer.len(), 3);
+
+        // Only what fits goes in
+        assert_eq!(producer.push_iter(&mut (5..20)), 5);
+        assert_eq!(producer.samples_written(), 10);
+    }
+
+    #[test]
+    fn clear_drops_only_what_was_already_pushed() {
+        let (mut producer, mut consumer) = sample_buffer::<i32>(16);
+        producer.push_iter(&mut (0..5));
+        assert_eq!(consumer.pop(), Some(0));
+        producer.clear();
+        producer.push_iter(&mut (100..103));
+
+        consumer.discard_stale();
+        assert_eq!(consumer.samples_read(), 5);
+        assert_eq!(pop_all(&mut consumer), vec![100, 101, 102]);
+        assert_eq!(consumer.samples_read(), producer.samples_written());
+    }
+
+    #[test]
+    fn discard_stale_without_a_clear_keeps_everything() {
+        let (mut producer, mut consumer) = sample_buffer::<i32>(16);
+        producer.push_iter(&mut (0..4));
+        consumer.discard_stale();
+        assert_eq!(consumer.samples_read(), 0);
+        assert_eq!(pop_all(&mut consumer), vec![0, 1, 2, 3]);
+
+        // Clearing an empty buffer, then discarding twice, doesn't count anything twice
+        producer.clear();
+        consumer.discard_stale();
+        consumer.discard_stale();
+        assert_eq!(consumer.samples_read(), 4);
+    }
+
+    #[test]
+    fn wait_for_space_returns_when_there_is_room() {
+        let (mut producer, _consumer) = sample_buffer::<i32>(4);
+        producer.push_iter(&mut (0..2));
+        assert!(producer.wait_for_space(2, || false));
+        // Asking for more than the whole buffer only waits for the whole buffer
+        let (producer, _consumer) = sample_buffer::<i32>(4);
+        assert!(producer.wait_for_space(100, || false));
+    }
+
+    #[test]
+    fn wait_for_space_can_be_interrupted() {
+        let (mut producer, _consumer) = sample_buffer::<i32>(4);
+        producer.push_iter(&mut (0..4));
+        assert!(!producer.wait_for_space(1, || true));
+
+        // From another thread, while it's blocked on a full buffer
+        let stopped = Arc::new(AtomicBool::new(false));
+        let wakeup = producer.wakeup();
+        let stopper = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                stopped.store(true, Ordering::SeqCst);
+                wakeup.wake();
+            })
+        };
+        assert!(!producer.wait_for_space(1, || stopped.load(Ordering::SeqCst)));
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn everything_arrives_in_order_across_threads() {
+        const TOTAL: usize = 100_000;
+        let (mut producer, mut consumer) = sample_buffer::<usize>(64);
+        let pushed = Arc::new(AtomicUsize::new(0));
+
+        // The producer pushes in uneven chunks, waiting for room like the decoder does
+        let producer_pushed = pushed.clone();
+        let decoder = thread::spawn(move || {
+            let mut next = 0;
+            while next < TOTAL {
+                let chunk = (next % 37 + 1).min(TOTAL - next);
+                producer.wait_for_space(chunk, || false);
+                next += producer.push_iter(&mut (next..next + chunk));
+                producer_pushed.store(next, Ordering::SeqCst);
+            }
+        });
+
+        let mut received = Vec::with_capacity(TOTAL);
+        while received.len() < TOTAL {
+            consumer.wait_for_samples(1, || false);
+            received.extend(pop_all(&mut consumer));
+            consumer.notify();
+        }
+        decoder.join().unwrap();
+
+        assert_eq!(pushed.load(Ordering::SeqCst), TOTAL);
+        assert!(received.iter().copied().eq(0..TOTAL));
+        assert_eq!(consumer.samples_read(), TOTAL as u64);
+    }
+}
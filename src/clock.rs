@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Works out what's coming out of the speakers right now.
+//
+// The decoder records anchors: the position in a track of a particular sample index in the
+// ring buffer (every sample ever pushed has an index). The output callback records how many
+// samples it has taken out of the buffer, and how far ahead of the speakers it's running.
+// Together those give the position of what's being heard, accounting for whatever is still
+// sitting in the ring buffer and in the device's own buffers.
+//
+// There can be more than one anchor when the decoder has moved on to the next track while the
+// end of the previous one is still in the buffer.
+pub(crate) struct Clock {
+    // Samples per second, counting every channel
+    samples_per_second: u64,
+    // Oldest first. Only the decoder and player touch these.
+    anchors: Mutex<VecDeque<Anchor>>,
+    // Updated by the output callback, so these have to be atomics
+    samples_read: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Anchor {
+    // Sample index in the ring buffer...
+    pub(crate) index: u64,
+    // ...and where that is in the track
+    pub(crate) position: Duration,
+    pub(crate) track: usize,
+    pub(crate) duration: Option<Duration>,
+}
+
+// What's being heard right now
+pub(crate) struct Playhead {
+    pub(crate) track: usize,
+    pub(crate) position: Duration,
+    pub(crate) duration: Option<Duration>,
+}
+
+impl Clock {
+    pub(crate) fn new(sample_rate: u32, channels: u16) -> Clock {
+        Clock {
+            samples_per_second: u64::from(sample_rate) * u64::from(channels),
+            anchors: Mutex::new(VecDeque::new()),
+            samples_read: AtomicU64::new(0),
+            latency_micros: AtomicU64::new(0),
+        }
+    }
+
+    // A new track starts at `anchor.index`. Anything already buffered still plays first.
+    pub(crate) fn push_anchor(&self, anchor: Anchor) {
+        self.anchors.lock().unwrap().push_back(anchor);
+    }
+
+    // After a seek everything buffered is thrown away, so only the new anchor counts
+    pub(crate) fn reset_anchor(&self, anchor: Anchor) {
+        let mut anchors = self.anchors.lock().unwrap();
+        anchors.clear();
+        anchors.push_back(anchor);
+    }
+
+    // Called from the output callback, with how far ahead of the speakers it's running
+    pub(crate) fn record_output(&self, samples_read: u64, latency: Duration) {
+        self.samples_read.store(samples_read, Ordering::Relaxed);
+        self.latency_micros.store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn now(&self) -> Playhead {
+        let latency = Duration::from_micros(self.latency_micros.load(Ordering::Relaxed));
+        let latency_samples = (latency.as_secs_f64() * self.samples_per_second as f64) as u64;
+        let heard = self.samples_read.load(Ordering::Relaxed).saturating_sub(latency_samples);
+
+        let mut anchors = self.anchors.lock().unwrap();
+
+        // Anchors we've played past for good aren't needed any more
+        while anchors.len() > 1 && anchors[1].index <= heard {
+            anchors.pop_front();
+        }
+
+        let anchor = match anchors.front() {
+            Some(anchor) => *anchor,
+            None => {
+                return Playhead {
+                    track: 0,
+                    position: Duration::from_secs(0),
+                    duration: None,
+                }
+            }
+        };
+
+        // Before the callback gets to the anchor (e.g. just after a seek) we're still right at it
+        let played = heard.saturating_sub(anchor.index) as f64 / self.samples_per_second as f64;
+        Playhead {
+            track: anchor.track,
+            position: anchor.position + Duration::from_secs_f64(played),
+            duration: anchor.duration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1000 Hz stereo, so 2000 samples a second
+    fn clock() -> Clock {
+        Clock::new(1000, 2)
+    }
+
+    fn anchor(index: u64, position_ms: u64, track: usize) -> Anchor {
+        Anchor {
+            index,
+            position: Duration::from_millis(position_ms),
+            track,
+            duration: Some(Duration::from_secs(track as u64 + 10)),
+        }
+    }
+
+    fn heard(clock: &Clock) -> (usize, Duration) {
+        let playhead = clock.now();
+        (playhead.track, playhead.position)
+    }
+
+    #[test]
+    fn nothing_anchored_is_the_start_of_the_first_track() {
+        let clock = clock();
+        clock.record_output(5000, Duration::from_secs(0));
+        assert_eq!(heard(&clock), (0, Duration::from_secs(0)));
+        assert_eq!(clock.now().duration, None);
+    }
+
+    #[test]
+    fn counts_from_the_anchor() {
+        let clock = clock();
+        clock.reset_anchor(anchor(1000, 30_000, 2));
+        clock.record_output(2000, Duration::from_secs(0));
+        assert_eq!(heard(&clock), (2, Duration::from_millis(30_500)));
+        assert_eq!(clock.now().duration, Some(Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn subtracts_the_latency() {
+        let clock = clock();
+        clock.reset_anchor(anchor(0, 0, 0));
+        // A second read, a quarter of it still in the device's buffers
+        clock.record_output(2000, Duration::from_millis(250));
+        assert_eq!(heard(&clock), (0, Duration::from_millis(750)));
+    }
+
+    #[test]
+    fn stays_at_the_anchor_until_the_callback_gets_there() {
+        let clock = clock();
+        clock.record_output(1000, Duration::from_secs(0));
+        // A seek to 5s, with the first sample after it not yet read
+        clock.reset_anchor(anchor(4000, 5000, 0));
+        assert_eq!(heard(&clock), (0, Duration::from_secs(5)));
+
+        // Latency bigger than everything read doesn't go below zero either
+        clock.record_output(100, Duration::from_secs(1));
+        assert_eq!(heard(&clock), (0, Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn moves_on_to_the_next_track_once_it_is_heard() {
+        let clock = clock();
+        clock.reset_anchor(anchor(0, 0, 0));
+        // The decoder gets to the next track a second and a half in
+        clock.push_anchor(anchor(3000, 0, 1));
+
+        clock.record_output(2000, Duration::from_secs(0));
+        assert_eq!(heard(&clock), (0, Duration::from_secs(1)));
+
+        // Read past the start of the next track, but it's still in the device's buffers
+        clock.record_output(3000, Duration::from_millis(250));
+        assert_eq!(heard(&clock), (0, Duration::from_millis(1250)));
+
+        clock.record_output(4000, Duration::from_millis(250));
+        assert_eq!(heard(&clock), (1, Duration::from_millis(250)));
+        assert_eq!(clock.anchors.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reset_forgets_the_tracks_still_to_come() {
+        let clock = clock();
+        clock.reset_anchor(anchor(0, 0, 0));
+        clock.push_anchor(anchor(3000, 0, 1));
+        clock.reset_anchor(anchor(2000, 10_000, 0));
+
+        clock.record_output(6000, Duration::from_secs(0));
+        assert_eq!(heard(&clock), (0, Duration::from_secs(12)));
+    }
+}
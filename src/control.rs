@@ -0,0 +1,159 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::error::PlayerError;
+
+/// Something to ask the player to do, sent through a `Controller`.
+#[derive(Debug)]
+pub enum Command {
+    Pause,
+    Resume,
+    /// Pause if playing, resume if paused
+    TogglePause,
+    Stop,
+    /// Jump to this position from the start of the track
+    Seek(Duration),
+    /// Go to the start of this track (an index into the playlist)
+    SkipTo(usize),
+    /// Switch to the audio stream with this index in the file, at the same position
+    SelectAudioStream(usize),
+    NextTrack,
+    /// Back to the start of this track, or to the previous one if this one has only just started
+    PreviousTrack,
+    /// Set the volume as a linear gain
+    SetVolume(f32),
+    /// Turn the volume up (or down, if negative) by this many decibels
+    AdjustVolumeDb(f32),
+    SetMuted(bool),
+    ToggleMute,
+    /// Reply with the player's current `Status`
+    Status(mpsc::Sender<Status>),
+}
+
+/// Where the player is up to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    /// Playing silence until enough of a stream has buffered
+    Buffering,
+    Stopped,
+    Finished,
+}
+
+/// A snapshot of the player, as returned by `Player::status` and `Controller::status`.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub state: PlaybackState,
+    /// Index of the track that's playing, in the list given to `Player::open_playlist` (or `open_entries`)
+    pub track: usize,
+    /// Position in the track of the audio that's currently coming out of the speakers
+    pub position: Duration,
+    pub duration: Option<Duration>,
+    /// Linear gain, ignoring mute
+    pub volume: f32,
+    pub muted: bool,
+    /// From an internet radio stream's ICY metadata
+    pub stream_title: Option<String>,
+    /// Index of the audio stream being played, in its file
+    pub audio_stream: usize,
+}
+
+/// Packets that were skipped because they wouldn't decode, as returned by `Player::decode_errors`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeErrors {
+    pub bad_packets: u64,
+    /// How much silence was played in their place
+    pub concealed: Duration,
+}
+
+// Everything the player thread can be woken up for
+#[derive(Debug)]
+pub(crate) enum Message {
+    Command(Command),
+    // The sink has failed
+    SinkError(PlayerError),
+    // The decoder thread has exited, whether it finished, was stopped or failed
+    DecoderDone,
+}
+
+/// Controls a `Player` from another thread.
+///
+/// Commands are queued up and handled by the thread that owns the `Player` while it's
+/// in `Player::wait` (or whenever it calls `Player::handle_commands`).
+#[derive(Clone)]
+pub struct Controller {
+    sender: mpsc::Sender<Message>,
+}
+
+impl Controller {
+    pub(crate) fn new(sender: mpsc::Sender<Message>) -> Controller {
+        Controller { sender }
+    }
+
+    /// Queue up a command. Returns false if the player has gone away.
+    pub fn send(&self, command: Command) -> bool {
+        self.sender.send(Message::Command(command)).is_ok()
+    }
+
+    pub fn pause(&self) -> bool {
+        self.send(Command::Pause)
+    }
+
+    pub fn resume(&self) -> bool {
+        self.send(Command::Resume)
+    }
+
+    pub fn toggle_pause(&self) -> bool {
+        self.send(Command::TogglePause)
+    }
+
+    pub fn stop(&self) -> bool {
+        self.send(Command::Stop)
+    }
+
+    pub fn seek(&self, position: Duration) -> bool {
+        self.send(Command::Seek(position))
+    }
+
+    pub fn skip_to(&self, track: usize) -> bool {
+        self.send(Command::SkipTo(track))
+    }
+
+    pub fn select_audio_stream(&self, index: usize) -> bool {
+        self.send(Command::SelectAudioStream(index))
+    }
+
+    pub fn next_track(&self) -> bool {
+        self.send(Command::NextTrack)
+    }
+
+    pub fn previous_track(&self) -> bool {
+        self.send(Command::PreviousTrack)
+    }
+
+    pub fn set_volume(&self, gain: f32) -> bool {
+        self.send(Command::SetVolume(gain))
+    }
+
+    pub fn adjust_volume_db(&self, db: f32) -> bool {
+        self.send(Command::AdjustVolumeDb(db))
+    }
+
+    pub fn set_muted(&self, muted: bool) -> bool {
+        self.send(Command::SetMuted(muted))
+    }
+
+    pub fn toggle_mute(&self) -> bool {
+        self.send(Command::ToggleMute)
+    }
+
+    /// Ask for the player's status and wait for the answer. Returns None if the player has gone away.
+    pub fn status(&self) -> Option<Status> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if !self.send(Command::Status(reply_tx)) {
+            return None;
+        }
+        reply_rx.recv().ok()
+    }
+}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::playlist::{decode_text, PlaylistEntry};
+
+/// A parsed CUE sheet: usually a whole album in one file, split into tracks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// One audio track from a CUE sheet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    /// Falls back to the sheet's performer if the track doesn't have its own
+    pub performer: Option<String>,
+    /// The audio file the track starts in
+    pub file: PathBuf,
+    /// INDEX 00, where the pregap starts, if there is one in the same file
+    pub pregap_start: Option<Duration>,
+    /// INDEX 01, where the track itself starts
+    pub start: Duration,
+    /// Where the next track in the same file starts, or None if this track runs to the end of the file
+    pub end: Option<Duration>,
+}
+
+impl CueTrack {
+    /// How long the pregap (INDEX 00 to INDEX 01) is.
+    pub fn pregap(&self) -> Option<Duration> {
+        self.pregap_start.map(|pregap_start| self.start.saturating_sub(pregap_start))
+    }
+}
+
+// CUE times are mm:ss:ff, with 75 frames a second (as on a CD)
+const FRAMES_PER_SECOND: u64 = 75;
+
+impl CueSheet {
+    /// Read and parse a `.cue` file. FILE paths are resolved against the sheet's directory.
+    pub fn read(path: &Path) -> io::Result<CueSheet> {
+        let text = decode_text(fs::read(path)?);
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(CueSheet::parse(&text, base))
+    }
+
+    /// Parse the contents of a CUE sheet.
+    ///
+    /// Only the commands that matter for playback are looked at (FILE, TRACK, INDEX, TITLE and
+    /// PERFORMER); everything else, and anything that doesn't parse, is ignored. Data tracks and
+    /// tracks without an INDEX 01 are left out.
+    pub fn parse(text: &str, base: &Path) -> CueSheet {
+        let mut sheet = CueSheet::default();
+        let mut file: Option<PathBuf> = None;
+        // The track being read, and the file it starts in (set by its INDEX 01)
+        let mut track: Option<PendingTrack> = None;
+        let mut tracks: Vec<(CueTrack, PathBuf)> = Vec::new();
+
+        for line in text.lines() {
+            let words = split_words(line.trim());
+            let (command, args) = match words.split_first() {
+                Some((command, args)) => (command.to_ascii_uppercase(), args),
+                None => continue,
+            };
+
+            match (command.as_str(), args) {
+                ("FILE", [name, ..]) => file = Some(base.join(name)),
+                ("TRACK", [number, kind, ..]) => {
+                    tracks.extend(track.take().and_then(PendingTrack::finish));
+                    track = match number.parse() {
+                        Ok(number) if kind.eq_ignore_ascii_case("AUDIO") => Some(PendingTrack::new(number)),
+                        _ => None,
+                    };
+                }
+                ("TITLE", [title, ..]) => match track.as_mut() {
+                    Some(track) => track.title = Some(title.clone()),
+                    None => sheet.title = Some(title.clone()),
+                },
+                ("PERFORMER", [performer, ..]) => match track.as_mut() {
+                    Some(track) => track.performer = Some(performer.clone()),
+                    None => sheet.performer = Some(performer.clone()),
+                },
+                ("INDEX", [number, time, ..]) => {
+                    if let (Some(track), Some(file), Some(time)) = (track.as_mut(), file.as_ref(), parse_time(time)) {
+                        match number.parse::<u32>() {
+                            Ok(0) => track.pregap_start = Some((time, file.clone())),
+                            Ok(1) => track.start = Some((time, file.clone())),
+                            _ => {}
+                        }
+                    }
+                }
+                // PREGAP and POSTGAP are silence that isn't in the file; there's nothing to play for those
+                _ => {}
+            }
+        }
+        tracks.extend(track.take().and_then(PendingTrack::finish));
+
+        // Each track runs up to the start of the next one in the same file. A pregap in the same file
+        // plays as the end of the track before, so the album plays straight through without gaps.
+        for i in 0..tracks.len() {
+            let end = tracks.get(i + 1).filter(|(_, file)| *file == tracks[i].1).map(|(next, _)| next.start);
+            let (mut track, _) = tracks[i].clone();
+            track.end = end;
+            if track.performer.is_none() {
+                track.performer = sheet.performer.clone();
+            }
+            sheet.tracks.push(track);
+        }
+        sheet
+    }
+
+    /// The tracks as playlist entries, for `Player::open_entries`.
+    pub fn entries(&self) -> Vec<PlaylistEntry> {
+        self.tracks
+            .iter()
+            .map(|track| PlaylistEntry {
+                path: track.file.clone(),
+                title: track.title.clone(),
+                performer: track.performer.clone(),
+                duration: track.end.map(|end| end.saturating_sub(track.start)),
+                start: track.start,
+                end: track.end,
+                reader: None,
+            })
+            .collect()
+    }
+}
+
+struct PendingTrack {
+    number: u32,
+    title: Option<String>,
+    performer: Option<String>,
+    pregap_start: Option<(Duration, PathBuf)>,
+    start: Option<(Duration, PathBuf)>,
+}
+
+impl PendingTrack {
+    fn new(number: u32) -> PendingTrack {
+        PendingTrack {
+            number,
+            title: None,
+            performer: None,
+            pregap_start: None,
+            start: None,
+        }
+    }
+
+    fn finish(self) -> Option<(CueTrack, PathBuf)> {
+        let (start, file) = self.start?;
+        // An INDEX 00 in the previous file is just the end of that file
+        let pregap_start = self.pregap_start.filter(|(_, pregap_file)| *pregap_file == file).map(|(time, _)| time);
+        let track = CueTrack {
+            number: self.number,
+            title: self.title,
+            performer: self.performer,
+            file: file.clone(),
+            pregap_start,
+            start,
+            end: None,
+        };
+        Some((track, file))
+    }
+}
+
+// Split a line into words, treating "quoted strings" as one word
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            words.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            words.push(word);
+        }
+    }
+    words
+}
+
+fn parse_time(time: &str) -> Option<Duration> {
+    let parts: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    match parts[..] {
+        [minutes, seconds, frames] if seconds < 60 && frames < FRAMES_PER_SECOND => {
+            let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+            Some(Duration::from_nanos(frames * 1_000_000_000 / FRAMES_PER_SECOND))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn times_are_minutes_seconds_and_frames() {
+        assert_eq!(parse_time("00:00:00"), Some(seconds(0)));
+        assert_eq!(parse_time("01:02:00"), Some(seconds(62)));
+        assert_eq!(parse_time("00:01:15"), Some(Duration::from_millis(1200)));
+        // More than 60 minutes is fine, but not 60 seconds or 75 frames
+        assert_eq!(parse_time("80:00:00"), Some(seconds(4800)));
+        assert_eq!(parse_time("00:60:00"), None);
+        assert_eq!(parse_time("00:00:75"), None);
+        assert_eq!(parse_time("00:00"), None);
+        assert_eq!(parse_time("aa:bb:cc"), None);
+    }
+
+    #[test]
+    fn tracks_end_where_the_next_one_starts() {
+        let text = r#"
+PERFORMER "The Band"
+TITLE "The Album"
+FILE "album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "One"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Two"
+    PERFORMER "Guest"
+    INDEX 00 03:58:00
+    INDEX 01 04:00:00
+  TRACK 03 AUDIO
+    TITLE "Three"
+    INDEX 01 07:30:00
+"#;
+        let sheet = CueSheet::parse(text, Path::new("/music"));
+
+        assert_eq!(sheet.title.as_deref(), Some("The Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.tracks.len(), 3);
+
+        let [one, two, three] = [&sheet.tracks[0], &sheet.tracks[1], &sheet.tracks[2]];
+        assert_eq!(one.file, PathBuf::from("/music/album.flac"));
+        assert_eq!((one.start, one.end), (seconds(0), Some(seconds(240))));
+        // The pregap plays as the end of the track before
+        assert_eq!(two.pregap_start, Some(seconds(238)));
+        assert_eq!(two.pregap(), Some(seconds(2)));
+        assert_eq!((two.start, two.end), (seconds(240), Some(seconds(450))));
+        assert_eq!((three.start, three.end), (seconds(450), None));
+
+        assert_eq!(one.performer.as_deref(), Some("The Band"));
+        assert_eq!(two.performer.as_deref(), Some("Guest"));
+
+        let entries = sheet.entries();
+        assert_eq!(entries[1].name(), "Guest - Two");
+        assert_eq!(entries[1].duration, Some(seconds(210)));
+        assert_eq!(entries[2].duration, None);
+    }
+
+    #[test]
+    fn tracks_in_separate_files_run_to_the_end_of_their_file() {
+        let text = r#"
+FILE "one.wav" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 04:58:00
+FILE "two.wav" WAVE
+    INDEX 01 00:00:00
+  TRACK 03 AUDIO
+    INDEX 01 03:00:00
+"#;
+        let sheet = CueSheet::parse(text, Path::new(""));
+        assert_eq!(sheet.tracks.len(), 3);
+
+        // Track 2's INDEX 00 is at the end of one.wav, so track 1 just plays to the end of its file
+        assert_eq!(sheet.tracks[0].file, PathBuf::from("one.wav"));
+        assert_eq!(sheet.tracks[0].end, None);
+        assert_eq!(sheet.tracks[1].file, PathBuf::from("two.wav"));
+        assert_eq!(sheet.tracks[1].pregap_start, None);
+        assert_eq!((sheet.tracks[1].start, sheet.tracks[1].end), (seconds(0), Some(seconds(180))));
+        assert_eq!((sheet.tracks[2].start, sheet.tracks[2].end), (seconds(180), None));
+    }
+
+    #[test]
+    fn data_tracks_and_tracks_without_index_01_are_left_out() {
+        let text = r#"
+FILE "disc.bin" BINARY
+  TRACK 01 MODE1/2352
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 01 10:00:00
+  TRACK 03 AUDIO
+    INDEX 00 12:00:00
+  TRACK 04 AUDIO
+    INDEX 01 15:00:00
+"#;
+        let sheet = CueSheet::parse(text, Path::new(""));
+        let numbers: Vec<u32> = sheet.tracks.iter().map(|track| track.number).collect();
+        assert_eq!(numbers, [2, 4]);
+        assert_eq!(sheet.tracks[0].end, Some(seconds(900)));
+    }
+
+    #[test]
+    fn quoted_words_keep_their_spaces() {
+        assert_eq!(split_words(r#"FILE "My Album.flac" WAVE"#), ["FILE", "My Album.flac", "WAVE"]);
+        assert_eq!(split_words("  INDEX 01   00:00:00 "), ["INDEX", "01", "00:00:00"]);
+    }
+}
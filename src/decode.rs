@@ -0,0 +1,336 @@
+use std::path::Path;
+use std::time::Duration;
+
+use ffmpeg::format::stream::Stream;
+use ffmpeg::format::{context::Input, input, input_with_dictionary, Sample as FFmpegSample};
+use ffmpeg::frame;
+use ffmpeg::media::Type as MediaType;
+use ffmpeg::{ChannelLayout, Rational};
+
+use crate::avio::{input_from_reader, CustomIo, Reader};
+use crate::error::PlayerError;
+use crate::network::{is_url, open_options};
+use crate::playlist::PlaylistEntry;
+
+/// What the decoder produces, before any resampling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub rate: u32,
+    pub channels: u16,
+    pub format: FFmpegSample,
+    /// How long the file is, if the container knows
+    pub duration: Option<Duration>,
+}
+
+// Open a file, or a URL with the options ffmpeg needs for streaming over a network
+pub(crate) fn open_path(path: &Path) -> Result<Input, PlayerError> {
+    if is_url(path) {
+        Ok(input_with_dictionary(&path, open_options())?)
+    } else {
+        Ok(input(&path)?)
+    }
+}
+
+/// Which audio stream to play, for files with more than one (films dubbed into several languages,
+/// multitrack recordings...).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum StreamSelector {
+    /// Whichever ffmpeg thinks is best (usually the default stream)
+    #[default]
+    Best,
+    /// The stream with this index in the container, as listed by `MediaInfo`. Files with no audio
+    /// stream at that index won't play.
+    Index(usize),
+    /// The first audio stream tagged with this language (like "eng"), or the best one if there isn't one
+    Language(String),
+    /// The first audio stream in this codec (like "ac3"), or the best one if there isn't one
+    Codec(String),
+}
+
+impl StreamSelector {
+    pub(crate) fn select<'a>(&self, ictx: &'a Input) -> Result<Stream<'a>, PlayerError> {
+        let mut audio = ictx.streams().filter(|stream| stream.parameters().medium() == MediaType::Audio);
+        let chosen = match self {
+            StreamSelector::Best => None,
+            StreamSelector::Index(index) => {
+                return audio
+                    .find(|stream| stream.index() == *index)
+                    .ok_or(PlayerError::AudioStreamNotFound(*index))
+            }
+            StreamSelector::Language(language) => audio.find(|stream| {
+                stream.metadata().get("language").is_some_and(|tag| tag.eq_ignore_ascii_case(language))
+            }),
+            StreamSelector::Codec(codec) => {
+                audio.find(|stream| stream.parameters().id().name().eq_ignore_ascii_case(codec))
+            }
+        };
+
+        match chosen.or_else(|| ictx.streams().best(MediaType::Audio)) {
+            Some(stream) => Ok(stream),
+            None => Err(ffmpeg::Error::StreamNotFound.into()),
+        }
+    }
+}
+
+// An opened file along with the decoder for the audio stream being played
+pub struct Source {
+    pub(crate) ictx: Input,
+    pub(crate) audio_stream_index: usize,
+    pub(crate) decoder: ffmpeg::decoder::Audio,
+    pub(crate) time_base: Rational,
+    // False for pipes, live streams and other things that can only go forwards
+    pub(crate) seekable: bool,
+    // Fetched over the network by ffmpeg, so the connection can drop
+    pub(crate) network: bool,
+    // For a file read through a Reader. It has to be dropped after `ictx`, so it goes last.
+    _io: Option<CustomIo>,
+}
+
+impl Source {
+    pub fn open(path: &Path) -> Result<Source, PlayerError> {
+        Source::open_with(path, &StreamSelector::Best)
+    }
+
+    /// Like `open`, but choosing which audio stream to play.
+    pub fn open_with(path: &Path, selector: &StreamSelector) -> Result<Source, PlayerError> {
+        Source::new(open_path(path)?, selector, true, is_url(path), None)
+    }
+
+    /// Open a file that's read through `reader` rather than from the filesystem.
+    pub fn from_reader(reader: Reader) -> Result<Source, PlayerError> {
+        Source::from_reader_with(reader, &StreamSelector::Best)
+    }
+
+    fn from_reader_with(reader: Reader, selector: &StreamSelector) -> Result<Source, PlayerError> {
+        let seekable = reader.is_seekable();
+        let (ictx, io) = input_from_reader(reader)?;
+        Source::new(ictx, selector, seekable, false, Some(io))
+    }
+
+    pub(crate) fn open_entry(entry: &PlaylistEntry, selector: &StreamSelector) -> Result<Source, PlayerError> {
+        match entry.reader.as_ref() {
+            Some(reader) => Source::from_reader_with(reader.take()?, selector),
+            None => Source::open_with(&entry.path, selector),
+        }
+    }
+
+    fn new(
+        ictx: Input,
+        selector: &StreamSelector,
+        seekable: bool,
+        network: bool,
+        io: Option<CustomIo>,
+    ) -> Result<Source, PlayerError> {
+        let (audio_stream_index, decoder, time_base) = open_decoder(selector.select(&ictx)?)?;
+
+        let mut source = Source {
+            ictx,
+            audio_stream_index,
+            decoder,
+            time_base,
+            seekable,
+            network,
+            _io: io,
+        };
+        source.seekable &= !source.is_live();
+        Ok(source)
+    }
+
+    // Switch to decoding another of the file's audio streams. The caller seeks to line it up.
+    pub(crate) fn select_stream(&mut self, index: usize) -> Result<(), PlayerError> {
+        let (audio_stream_index, decoder, time_base) = open_decoder(StreamSelector::Index(index).select(&self.ictx)?)?;
+        self.audio_stream_index = audio_stream_index;
+        self.decoder = decoder;
+        self.time_base = time_base;
+        Ok(())
+    }
+
+    // Internet radio and the like: fetched over the network, with no end in sight
+    pub(crate) fn is_live(&self) -> bool {
+        self.network && self.duration().is_none()
+    }
+
+    pub fn decoder(&self) -> &ffmpeg::decoder::Audio {
+        &self.decoder
+    }
+
+    // When the file's timeline starts, in AV_TIME_BASE units (usually 0, but not for MPEG-TS and friends)
+    pub fn start_time(&self) -> i64 {
+        match unsafe { (*self.ictx.as_ptr()).start_time } {
+            ffmpeg::ffi::AV_NOPTS_VALUE => 0,
+            start_time => start_time,
+        }
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        match self.ictx.duration() {
+            duration if duration < 0 => None,
+            duration => Some(Duration::from_micros(duration as u64)),
+        }
+    }
+
+    // Some files (raw PCM, some WAVs) don't say what their layout is, so guess from the channel count
+    pub fn channel_layout(&self) -> ChannelLayout {
+        let layout = self.decoder.channel_layout();
+        if layout.is_empty() {
+            ChannelLayout::default(i32::from(self.decoder.channels()))
+        } else {
+            layout
+        }
+    }
+
+    pub fn info(&self) -> StreamInfo {
+        StreamInfo {
+            rate: self.decoder.rate(),
+            channels: self.decoder.channels(),
+            format: self.decoder.format(),
+            duration: self.duration(),
+        }
+    }
+}
+
+// A decoder for `stream`, along with its index and time base
+fn open_decoder(stream: Stream) -> Result<(usize, ffmpeg::decoder::Audio, Rational), PlayerError> {
+    let decoder = stream.codec().decoder().audio()?;
+    Ok((stream.index(), decoder, stream.time_base()))
+}
+
+// Interpret the audio frame's data as packed (alternating channels, 12121212, as opposed to planar 11112222).
+// Use `planar` for planar frames.
+pub fn packed<T: frame::audio::Sample>(frame: &frame::Audio) -> Result<&[T], PlayerError> {
+    if !frame.is_packed() {
+        return Err(PlayerError::UnsupportedFormat(format!("{} data is not packed", frame.format().name())));
+    }
+
+    if !<T as frame::audio::Sample>::is_valid(frame.format(), frame.channels()) {
+        return Err(PlayerError::UnsupportedFormat(format!(
+            "can't read {} data with {} channels as {}",
+            frame.format().name(),
+            frame.channels(),
+            std::any::type_name::<T>()
+        )));
+    }
+
+    Ok(unsafe { std::slice::from_raw_parts((*frame.as_ptr()).data[0] as *const T, frame.samples() * frame.channels() as usize) })
+}
+
+// Interpret a planar audio frame's data as one slice per channel (11112222 becomes [1111, 2222])
+pub fn planar<T: frame::audio::Sample>(frame: &frame::Audio) -> Result<Vec<&[T]>, PlayerError> {
+    if !frame.is_planar() {
+        return Err(PlayerError::UnsupportedFormat(format!("{} data is not planar", frame.format().name())));
+    }
+
+    // Each plane holds a single channel
+    if !<T as frame::audio::Sample>::is_valid(frame.format(), 1) {
+        return Err(PlayerError::UnsupportedFormat(format!(
+            "can't read {} data as {}",
+            frame.format().name(),
+            std::any::type_name::<T>()
+        )));
+    }
+
+    // extended_data rather than data, which only has room for the first 8 channels
+    let planes = unsafe { (*frame.as_ptr()).extended_data };
+    Ok((0..frame.channels() as usize)
+        .map(|channel| unsafe { std::slice::from_raw_parts(*planes.add(channel) as *const T, frame.samples()) })
+        .collect())
+}
+
+// Interleave one slice per channel (as from `planar`) into `out`, replacing what was there: [1111, 2222] becomes 12121212
+pub fn interleave<T: Copy>(planes: &[&[T]], out: &mut Vec<T>) {
+    out.clear();
+    let frames = planes.iter().map(|plane| plane.len()).min().unwrap_or(0);
+    out.reserve(frames * planes.len());
+    for frame in 0..frames {
+        out.extend(planes.iter().map(|plane| plane[frame]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg::format::sample::Type as SampleType;
+
+    // A frame of `samples` samples per channel, each plane counting up from its channel number times 100
+    fn planar_frame(samples: usize, layout: ChannelLayout) -> frame::Audio {
+        let mut frame = frame::Audio::new(FFmpegSample::F32(SampleType::Planar), samples, layout);
+        // Through extended_data, since plane_mut only reaches the first 8 planes
+        let planes = unsafe { (*frame.as_mut_ptr()).extended_data };
+        for channel in 0..frame.channels() as usize {
+            let plane = unsafe { std::slice::from_raw_parts_mut(*planes.add(channel) as *mut f32, samples) };
+            for (i, sample) in plane.iter_mut().enumerate() {
+                *sample = (channel * 100 + i) as f32;
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn interleaves_planes() {
+        let mut out = Vec::new();
+        interleave(&[&[1, 2, 3][..], &[4, 5, 6][..]], &mut out);
+        assert_eq!(out, vec![1, 4, 2, 5, 3, 6]);
+    }
+
+    #[test]
+    fn interleaving_stops_at_the_shortest_plane() {
+        let mut out = Vec::new();
+        interleave(&[&[1, 2, 3][..], &[4][..]], &mut out);
+        assert_eq!(out, vec![1, 4]);
+    }
+
+    #[test]
+    fn interleaving_nothing_gives_nothing() {
+        let mut out = vec![9, 9];
+        interleave::<i32>(&[], &mut out);
+        assert!(out.is_empty());
+
+        interleave(&[&[][..], &[1][..]], &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn interleaving_replaces_what_was_in_the_buffer() {
+        let mut out = vec![7, 7, 7, 7, 7, 7, 7, 7];
+        interleave(&[&[1, 2][..], &[3, 4][..]], &mut out);
+        assert_eq!(out, vec![1, 3, 2, 4]);
+
+        // The same buffer again, with room to spare from last time
+        let capacity = out.capacity();
+        interleave(&[&[5][..], &[6][..]], &mut out);
+        assert_eq!(out, vec![5, 6]);
+        assert_eq!(out.capacity(), capacity);
+    }
+
+    #[test]
+    fn reads_every_plane_of_a_planar_frame() {
+        let frame = planar_frame(4, ChannelLayout::STEREO);
+        let planes = planar::<f32>(&frame).unwrap();
+        assert_eq!(planes, vec![&[0.0, 1.0, 2.0, 3.0][..], &[100.0, 101.0, 102.0, 103.0][..]]);
+
+        let mut out = Vec::new();
+        interleave(&planes, &mut out);
+        assert_eq!(out, vec![0.0, 100.0, 1.0, 101.0, 2.0, 102.0, 3.0, 103.0]);
+    }
+
+    #[test]
+    fn reads_planes_past_the_eighth_channel() {
+        // More channels than AVFrame::data has room for, so the last ones are only in extended_data
+        let layout = ChannelLayout::HEXADECAGONAL;
+        let frame = planar_frame(2, layout);
+        let planes = planar::<f32>(&frame).unwrap();
+        assert_eq!(planes.len(), layout.channels() as usize);
+        assert_eq!(planes[15], &[1500.0, 1501.0][..]);
+    }
+
+    #[test]
+    fn planar_and_packed_check_the_layout_and_type() {
+        let frame = planar_frame(4, ChannelLayout::STEREO);
+        assert!(packed::<f32>(&frame).is_err());
+        assert!(planar::<i16>(&frame).is_err());
+
+        let packed_frame = frame::Audio::new(FFmpegSample::F32(SampleType::Packed), 4, ChannelLayout::STEREO);
+        assert!(planar::<f32>(&packed_frame).is_err());
+        assert_eq!(packed::<f32>(&packed_frame).unwrap().len(), 8);
+    }
+}
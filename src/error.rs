@@ -0,0 +1,140 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong opening or playing a file.
+#[derive(Debug)]
+pub enum PlayerError {
+    /// ffmpeg couldn't open, demux, decode or resample the file
+    Ffmpeg(ffmpeg::Error),
+    /// The file's audio (or a frame of it) is in a format we can't handle
+    UnsupportedFormat(String),
+    /// There's no audio host with this name
+    HostNotFound(String),
+    /// No output device matched this name or index
+    DeviceNotFound(String),
+    /// The host has no default output device
+    NoDefaultDevice,
+    /// Listing the host's devices failed
+    Devices(cpal::DevicesError),
+    /// Asking the device what configs it supports failed
+    SupportedConfigs(cpal::SupportedStreamConfigsError),
+    /// The device doesn't support any config we can play through
+    NoSupportedConfig,
+    /// A custom mix matrix doesn't fit the file and device channel counts
+    MixMatrix { inputs: usize, outputs: usize },
+    BuildStream(cpal::BuildStreamError),
+    PlayStream(cpal::PlayStreamError),
+    PauseStream(cpal::PauseStreamError),
+    /// The output stream failed while playing (e.g. the device was unplugged)
+    Stream(cpal::StreamError),
+    /// The decoder thread panicked or went away unexpectedly
+    DecoderThread,
+    /// A sink's output thread panicked
+    SinkThread,
+    /// `AudioSink::start` was called before `configure`
+    SinkNotConfigured,
+    /// There were no files to play
+    EmptyPlaylist,
+    /// The file has no audio stream with the index that was asked for
+    AudioStreamNotFound(usize),
+    /// Reading a directory or file failed
+    Io(io::Error),
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ffmpeg(e) => write!(f, "ffmpeg error: {}", e),
+            Self::UnsupportedFormat(format) => write!(f, "unsupported audio format: {}", format),
+            Self::HostNotFound(name) => {
+                let available: Vec<_> = cpal::available_hosts().iter().map(|id| id.name()).collect();
+                write!(f, "no audio host named '{}' (available: {})", name, available.join(", "))
+            }
+            Self::DeviceNotFound(name) => write!(f, "no output device matching '{}'", name),
+            Self::NoDefaultDevice => write!(f, "no default output device available"),
+            Self::Devices(e) => write!(f, "error listing output devices: {}", e),
+            Self::SupportedConfigs(e) => write!(f, "error querying audio output configs: {}", e),
+            Self::NoSupportedConfig => write!(f, "the output device has no supported audio config"),
+            Self::MixMatrix { inputs, outputs } => write!(
+                f,
+                "the mix matrix needs {} rows (output channels) of {} weights (input channels)",
+                outputs, inputs
+            ),
+            Self::BuildStream(e) => write!(f, "error opening the audio output stream: {}", e),
+            Self::PlayStream(e) => write!(f, "error starting the audio output stream: {}", e),
+            Self::PauseStream(e) => write!(f, "error pausing the audio output stream: {}", e),
+            Self::Stream(e) => write!(f, "error on the audio output stream: {}", e),
+            Self::DecoderThread => write!(f, "the decoder thread exited unexpectedly"),
+            Self::SinkThread => write!(f, "the audio output thread exited unexpectedly"),
+            Self::SinkNotConfigured => write!(f, "the audio output was started before it was configured"),
+            Self::EmptyPlaylist => write!(f, "nothing to play"),
+            Self::AudioStreamNotFound(index) => write!(f, "stream #{} isn't an audio stream in this file", index),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PlayerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Ffmpeg(e) => Some(e),
+            Self::Devices(e) => Some(e),
+            Self::SupportedConfigs(e) => Some(e),
+            Self::BuildStream(e) => Some(e),
+            Self::PlayStream(e) => Some(e),
+            Self::PauseStream(e) => Some(e),
+            Self::Stream(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ffmpeg::Error> for PlayerError {
+    fn from(e: ffmpeg::Error) -> Self {
+        Self::Ffmpeg(e)
+    }
+}
+
+impl From<cpal::DevicesError> for PlayerError {
+    fn from(e: cpal::DevicesError) -> Self {
+        Self::Devices(e)
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for PlayerError {
+    fn from(e: cpal::SupportedStreamConfigsError) -> Self {
+        Self::SupportedConfigs(e)
+    }
+}
+
+impl From<cpal::BuildStreamError> for PlayerError {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        Self::BuildStream(e)
+    }
+}
+
+impl From<cpal::PlayStreamError> for PlayerError {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        Self::PlayStream(e)
+    }
+}
+
+impl From<cpal::PauseStreamError> for PlayerError {
+    fn from(e: cpal::PauseStreamError) -> Self {
+        Self::PauseStream(e)
+    }
+}
+
+impl From<cpal::StreamError> for PlayerError {
+    fn from(e: cpal::StreamError) -> Self {
+        Self::Stream(e)
+    }
+}
+
+impl From<io::Error> for PlayerError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -0,0 +1,381 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::time::Duration;
+
+use ffmpeg::format::context::Input;
+use ffmpeg::format::Sample as FFmpegSample;
+use ffmpeg::media::Type as MediaType;
+use ffmpeg::{codec, ffi, DictionaryRef, Rational};
+
+use crate::avio::{input_from_reader, Reader};
+use crate::decode::{open_path, StreamSelector};
+use crate::error::PlayerError;
+use crate::playlist::PlaylistEntry;
+
+/// What's in a file: its tags, and every stream in the container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaInfo {
+    /// The container format's short name, like "flac" or "mov,mp4,m4a,3gp,3g2,mj2"
+    pub format: String,
+    /// The container format's full name, like "raw FLAC"
+    pub format_description: String,
+    pub duration: Option<Duration>,
+    /// Bits per second, over the whole file
+    pub bit_rate: Option<u64>,
+    /// The container's tags, in the order they're stored
+    pub tags: Vec<(String, String)>,
+    pub streams: Vec<StreamDetails>,
+    /// Which of `streams` the player would play
+    pub audio_stream: Option<usize>,
+}
+
+/// One stream in a container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamDetails {
+    pub index: usize,
+    /// "audio", "video", "subtitle", "data", "attachment" or "unknown"
+    pub kind: &'static str,
+    pub codec: String,
+    pub bit_rate: Option<u64>,
+    pub duration: Option<Duration>,
+    /// From the stream's `language` tag
+    pub language: Option<String>,
+    pub tags: Vec<(String, String)>,
+    /// For audio streams
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    /// Like "stereo" or "5.1(side)"
+    pub channel_layout: Option<String>,
+    /// Like "fltp" or "s16"
+    pub sample_format: Option<String>,
+    /// Width and height, for video streams (including cover art)
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl MediaInfo {
+    /// Read the details of a file (or URL) without decoding any of it.
+    pub fn read(path: &Path) -> Result<MediaInfo, PlayerError> {
+        MediaInfo::read_selecting(path, &StreamSelector::Best)
+    }
+
+    /// Like `read`, for a file that's read through `reader`.
+    pub fn from_reader(reader: Reader) -> Result<MediaInfo, PlayerError> {
+        MediaInfo::from_reader_selecting(reader, &StreamSelector::Best)
+    }
+
+    /// Like `read`, for a playlist entry (which might be read through a `Reader`).
+    pub fn read_entry(entry: &PlaylistEntry) -> Result<MediaInfo, PlayerError> {
+        MediaInfo::read_entry_with(entry, &StreamSelector::Best)
+    }
+
+    /// Like `read_entry`, with `audio_stream` set to the stream `selector` picks, the same as the player would.
+    pub fn read_entry_with(entry: &PlaylistEntry, selector: &StreamSelector) -> Result<MediaInfo, PlayerError> {
+        match entry.reader.as_ref() {
+            Some(reader) => MediaInfo::from_reader_selecting(reader.take()?, selector),
+            None => MediaInfo::read_selecting(&entry.path, selector),
+        }
+    }
+
+    fn read_selecting(path: &Path, selector: &StreamSelector) -> Result<MediaInfo, PlayerError> {
+        ffmpeg::init()?;
+        Ok(MediaInfo::from_input(&open_path(path)?, selector))
+    }
+
+    fn from_reader_selecting(reader: Reader, selector: &StreamSelector) -> Result<MediaInfo, PlayerError> {
+        ffmpeg::init()?;
+        let (ictx, io) = input_from_reader(reader)?;
+        let info = MediaInfo::from_input(&ictx, selector);
+        // The reader has to outlive the context reading from it
+        drop(ictx);
+        drop(io);
+        Ok(info)
+    }
+
+    fn from_input(ictx: &Input, selector: &StreamSelector) -> MediaInfo {
+        let format = ictx.format();
+        let bit_rate = unsafe { (*ictx.as_ptr()).bit_rate };
+
+        MediaInfo {
+            format: format.name().to_string(),
+            format_description: format.description().to_string(),
+            duration: match ictx.duration() {
+                duration if duration < 0 => None,
+                duration => Some(Duration::from_micros(duration as u64)),
+            },
+            bit_rate: positive(bit_rate),
+            tags: tags(ictx.metadata()),
+            streams: ictx.streams().map(|stream| StreamDetails::new(&stream)).collect(),
+            // None if the selector doesn't match anything, in which case the player wouldn't play it either
+            audio_stream: selector.select(ictx).ok().map(|stream| stream.index()),
+        }
+    }
+
+    /// A tag from the container, or failing that from the audio stream (where Ogg keeps them).
+    /// Keys are matched ignoring case.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        let stream_tags = self.audio_stream.and_then(|index| self.streams.get(index)).map(|stream| &stream.tags);
+        find_tag(&self.tags, key).or_else(|| find_tag(stream_tags?, key))
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.tag("title")
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.tag("artist")
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.tag("album")
+    }
+
+    /// The track number, as it's written in the file (often "3/12")
+    pub fn track(&self) -> Option<&str> {
+        self.tag("track")
+    }
+
+    /// Just the audio streams, for choosing one with `StreamSelector::Index`.
+    pub fn audio_streams(&self) -> impl Iterator<Item = &StreamDetails> {
+        self.streams.iter().filter(|stream| stream.kind == "audio")
+    }
+
+    /// Everything as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        let streams: Vec<_> = self.streams.iter().map(StreamDetails::to_json).collect();
+        let fields = [
+            ("format", json_string(&self.format)),
+            ("format_description", json_string(&self.format_description)),
+            ("duration", json_option(self.duration.map(|duration| duration.as_secs_f64()))),
+            ("bit_rate", json_option(self.bit_rate)),
+            ("title", json_option(self.title().map(json_string))),
+            ("artist", json_option(self.artist().map(json_string))),
+            ("album", json_option(self.album().map(json_string))),
+            ("track", json_option(self.track().map(json_string))),
+            ("tags", json_tags(&self.tags)),
+            ("audio_stream", json_option(self.audio_stream)),
+            ("streams", format!("[{}]", streams.join(","))),
+        ];
+        json_object(&fields)
+    }
+}
+
+impl StreamDetails {
+    fn new(stream: &ffmpeg::format::stream::Stream) -> StreamDetails {
+        let parameters = unsafe { &*(*stream.as_ptr()).codecpar };
+        let kind = match MediaType::from(parameters.codec_type) {
+            MediaType::Audio => "audio",
+            MediaType::Video => "video",
+            MediaType::Subtitle => "subtitle",
+            MediaType::Data => "data",
+            MediaType::Attachment => "attachment",
+            MediaType::Unknown => "unknown",
+        };
+        let tags = tags(stream.metadata());
+
+        let (sample_rate, channels, channel_layout, sample_format) = if kind == "audio" {
+            (
+                positive(i64::from(parameters.sample_rate)).map(|rate| rate as u32),
+                positive(i64::from(parameters.channels)).map(|channels| channels as u16),
+                layout_name(parameters.channels, parameters.channel_layout),
+                sample_format_name(parameters.format),
+            )
+        } else {
+            (None, None, None, None)
+        };
+
+        StreamDetails {
+            index: stream.index(),
+            kind,
+            codec: codec::Id::from(parameters.codec_id).name().to_string(),
+            bit_rate: positive(parameters.bit_rate),
+            duration: stream_duration(stream.duration(), stream.time_base()),
+            language: find_tag(&tags, "language").map(str::to_string),
+            tags,
+            sample_rate,
+            channels,
+            channel_layout,
+            sample_format,
+            dimensions: match (kind, parameters.width, parameters.height) {
+                ("video", width, height) if width > 0 && height > 0 => Some((width as u32, height as u32)),
+                _ => None,
+            },
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let fields = [
+            ("index", self.index.to_string()),
+            ("kind", json_string(self.kind)),
+            ("codec", json_string(&self.codec)),
+            ("bit_rate", json_option(self.bit_rate)),
+            ("duration", json_option(self.duration.map(|duration| duration.as_secs_f64()))),
+            ("language", json_option(self.language.as_deref().map(json_string))),
+            ("sample_rate", json_option(self.sample_rate)),
+            ("channels", json_option(self.channels)),
+            ("channel_layout", json_option(self.channel_layout.as_deref().map(json_string))),
+            ("sample_format", json_option(self.sample_format.as_deref().map(json_string))),
+            ("width", json_option(self.dimensions.map(|(width, _)| width))),
+            ("height", json_option(self.dimensions.map(|(_, height)| height))),
+            ("tags", json_tags(&self.tags)),
+        ];
+        json_object(&fields)
+    }
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Format:   {} ({})", self.format, self.format_description)?;
+        if let Some(duration) = self.duration {
+            writeln!(f, "Duration: {}", format_duration(duration))?;
+        }
+        if let Some(bit_rate) = self.bit_rate {
+            writeln!(f, "Bit rate: {} kb/s", bit_rate / 1000)?;
+        }
+        let names = [
+            ("Title", self.title()),
+            ("Artist", self.artist()),
+            ("Album", self.album()),
+            ("Track", self.track()),
+        ];
+        for (name, value) in names {
+            if let Some(value) = value {
+                writeln!(f, "{}:{:width$}{}", name, "", value, width = 9 - name.len())?;
+            }
+        }
+
+        if !self.tags.is_empty() {
+            writeln!(f, "Tags:")?;
+            for (key, value) in &self.tags {
+                writeln!(f, "  {}: {}", key, value)?;
+            }
+        }
+
+        writeln!(f, "Streams:")?;
+        for stream in &self.streams {
+            let playing = if Some(stream.index) == self.audio_stream { " (plays)" } else { "" };
+            writeln!(f, "  #{}: {}{}", stream.index, stream, playing)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StreamDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.codec)?;
+        if let Some(language) = self.language.as_ref() {
+            write!(f, " [{}]", language)?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            write!(f, ", {} Hz", sample_rate)?;
+        }
+        match (self.channel_layout.as_ref(), self.channels) {
+            (Some(layout), _) => write!(f, ", {}", layout)?,
+            (None, Some(channels)) => write!(f, ", {} channels", channels)?,
+            (None, None) => {}
+        }
+        if let Some(sample_format) = self.sample_format.as_ref() {
+            write!(f, ", {}", sample_format)?;
+        }
+        if let Some((width, height)) = self.dimensions {
+            write!(f, ", {}x{}", width, height)?;
+        }
+        if let Some(bit_rate) = self.bit_rate {
+            write!(f, ", {} kb/s", bit_rate / 1000)?;
+        }
+        if let Some(duration) = self.duration {
+            write!(f, ", {}", format_duration(duration))?;
+        }
+        Ok(())
+    }
+}
+
+fn tags(metadata: DictionaryRef) -> Vec<(String, String)> {
+    metadata.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+fn find_tag<'a>(tags: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    tags.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, value)| value.as_str())
+}
+
+// ffmpeg uses 0 (or less) for "don't know"
+fn positive(value: i64) -> Option<u64> {
+    if value > 0 {
+        Some(value as u64)
+    } else {
+        None
+    }
+}
+
+fn stream_duration(duration: i64, time_base: Rational) -> Option<Duration> {
+    if duration == ffi::AV_NOPTS_VALUE || duration < 0 || time_base.denominator() == 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(duration as f64 * f64::from(time_base)))
+}
+
+fn layout_name(channels: i32, layout: u64) -> Option<String> {
+    if channels <= 0 {
+        return None;
+    }
+    let mut name = [0 as c_char; 64];
+    unsafe {
+        ffi::av_get_channel_layout_string(name.as_mut_ptr(), name.len() as i32, channels, layout);
+        Some(CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned())
+    }
+}
+
+fn sample_format_name(format: i32) -> Option<String> {
+    // Only valid values can be turned into the enum
+    if format < 0 || format >= ffi::AVSampleFormat::AV_SAMPLE_FMT_NB as i32 {
+        return None;
+    }
+    let format: ffi::AVSampleFormat = unsafe { std::mem::transmute(format) };
+    Some(FFmpegSample::from(format).name().to_string())
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let tenths = duration.subsec_millis() / 100;
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}.{}", seconds / 3600, seconds / 60 % 60, seconds % 60, tenths)
+    } else {
+        format!("{:02}:{:02}.{}", seconds / 60, seconds % 60, tenths)
+    }
+}
+
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<_> = fields.iter().map(|(key, value)| format!("{}:{}", json_string(key), value)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_tags(tags: &[(String, String)]) -> String {
+    let tags: Vec<_> = tags.iter().map(|(key, value)| (key.as_str(), json_string(value))).collect();
+    json_object(&tags)
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -0,0 +1,49 @@
+//! Play audio files with ffmpeg doing the decoding and cpal doing the output.
+//!
+//! ```no_run
+//! let mut player = ffmpeg_cpal_play_audio::Player::open("song.mp3")?;
+//! player.play()?;
+//! player.wait()?;
+//! # Ok::<(), ffmpeg_cpal_play_audio::PlayerError>(())
+//! ```
+
+extern crate ffmpeg_next as ffmpeg;
+
+mod avio;
+mod buffer;
+mod clock;
+mod control;
+mod cue;
+mod decode;
+mod error;
+mod info;
+mod mix;
+mod network;
+mod output;
+mod pipeline;
+mod player;
+mod playlist;
+mod render;
+mod sink;
+mod volume;
+mod wav;
+
+pub use avio::{ReadSeek, Reader};
+pub use buffer::{sample_buffer, SampleConsumer, SampleProducer};
+pub use control::{Command, Controller, DecodeErrors, PlaybackState, Status};
+pub use cue::{CueSheet, CueTrack};
+pub use decode::{interleave, packed, planar, Source, StreamInfo, StreamSelector};
+pub use error::PlayerError;
+pub use info::{MediaInfo, StreamDetails};
+pub use mix::{output_layout, MixMatrix};
+pub use network::{is_url, parse_icy_title};
+pub use output::{
+    find_device, find_host, init_cpal, negotiate_config, output_devices, select_device, write_audio, CpalSink,
+    OutputDeviceInfo, OutputSample, SampleFormatConversion, SampleRatePolicy,
+};
+pub use player::{DecodeErrorHandler, Player, PlayerOptions, SkipHandler};
+pub use playlist::{expand_paths, is_playlist, parse_m3u, parse_pls, read_playlist, PlaylistEntry};
+pub use render::{render_to_wav, RenderOptions};
+pub use sink::{AudioSink, ErrorCallback, FileFormat, FileSink, NullSink, SampleCallback, SinkCallback, SinkConfig};
+pub use volume::{db_to_gain, gain_to_db, Volume, MIN_DB};
+pub use wav::{WavSample, WavWriter};
@@ -1,160 +1,546 @@
 extern crate ffmpeg_next as ffmpeg;
 
-use cpal::{Sample, SampleFormat};
-use ffmpeg::format::sample::Type as SampleType;
-use ffmpeg::format::{Sample as FFmpegSample, input};
-use ffmpeg::frame;
-use ffmpeg::media::Type as MediaType;
-use ffmpeg::software::resampling::{context::Context as ResamplingContext};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use ringbuf::RingBuffer;
-
-trait SampleFormatConversion {
-    fn as_ffmpeg_sample(&self) -> FFmpegSample;
-}
-
-impl SampleFormatConversion for SampleFormat {
-    fn as_ffmpeg_sample(&self) -> FFmpegSample {
-        match self {
-            Self::I16 => FFmpegSample::I16(SampleType::Packed),
-            Self::U16 => {
-                panic!("ffmpeg resampler doesn't support u16")
-            }, 
-            Self::F32 => FFmpegSample::F32(SampleType::Packed)
+use std::io::{BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use ffmpeg_cpal_play_audio::{
+    expand_paths, find_host, is_url, output_devices, render_to_wav, select_device, Controller, DecodeErrors,
+    MediaInfo, MixMatrix, PlaybackState, Player, PlayerError, PlayerOptions, PlaylistEntry, RenderOptions,
+    SampleRatePolicy, SkipHandler, StreamSelector,
+};
+
+const USAGE: &str = "usage: ffmpeg-cpal-play-audio [--host <name>] [--device <name|index>] [--rate <native|max|hz>]
+                              [--mix <matrix>] [--buffer <ms>] [--prebuffer <ms>] [--strict]
+                              [--track <n>] [--start <time>] [<stream choice>] <file|dir|playlist|url|->...
+       ffmpeg-cpal-play-audio --render <out.wav> [--rate <native|hz>] [--channels <n>] [--format <i16|f32>]
+                              [--mix <matrix>] [--strict] [<stream choice>] <file|dir|playlist|->...
+       ffmpeg-cpal-play-audio --info [--json] <file|dir|playlist|url|->...
+       ffmpeg-cpal-play-audio --audio-streams <file|dir|playlist|url>...
+       ffmpeg-cpal-play-audio --list-devices
+
+A stream choice is one of --audio-stream <index>, --audio-lang <code> or --audio-codec <name>.
+Packets that won't decode are skipped and replaced with silence; --strict stops at the first one instead.
+A file named - is read from standard input. URLs get 2 seconds of prebuffering unless --prebuffer says otherwise.";
+
+#[derive(Default)]
+struct Args {
+    files: Vec<String>,
+    host: Option<String>,
+    device: Option<String>,
+    sample_rate: SampleRatePolicy,
+    mix_matrix: Option<MixMatrix>,
+    buffer_ms: Option<u32>,
+    prebuffer_ms: Option<u32>,
+    start: Option<Duration>,
+    // 1-based, as it's shown
+    track: Option<usize>,
+    list_devices: bool,
+    info: bool,
+    json: bool,
+    list_audio_streams: bool,
+    strict: bool,
+    audio_stream: StreamSelector,
+    render: Option<PathBuf>,
+    channels: Option<u16>,
+    sample_format: Option<cpal::SampleFormat>,
+}
+
+impl Args {
+    fn parse() -> Result<Args, String> {
+        let mut args = Args::default();
+        let mut iter = std::env::args().skip(1);
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--list-devices" => args.list_devices = true,
+                "--info" => args.info = true,
+                "--json" => args.json = true,
+                "--audio-streams" => args.list_audio_streams = true,
+                "--strict" => args.strict = true,
+                "--audio-stream" => {
+                    let index = iter.next().ok_or("--audio-stream needs a value")?;
+                    args.audio_stream = StreamSelector::Index(
+                        index.parse().map_err(|_| format!("--audio-stream must be a stream index, not {}", index))?,
+                    );
+                }
+                "--audio-lang" => args.audio_stream = StreamSelector::Language(iter.next().ok_or("--audio-lang needs a value")?),
+                "--audio-codec" => args.audio_stream = StreamSelector::Codec(iter.next().ok_or("--audio-codec needs a value")?),
+                "--host" => args.host = Some(iter.next().ok_or("--host needs a value")?),
+                "--device" => args.device = Some(iter.next().ok_or("--device needs a value")?),
+                "--rate" => args.sample_rate = parse_rate(&iter.next().ok_or("--rate needs a value")?)?,
+                "--buffer" => {
+                    let ms = iter.next().ok_or("--buffer needs a value")?;
+                    args.buffer_ms = Some(ms.parse().map_err(|_| format!("--buffer must be a number of milliseconds, not {}", ms))?);
+                }
+                "--prebuffer" => {
+                    let ms = iter.next().ok_or("--prebuffer needs a value")?;
+                    args.prebuffer_ms = Some(ms.parse().map_err(|_| format!("--prebuffer must be a number of milliseconds, not {}", ms))?);
+                }
+                "--start" => {
+                    let time = iter.next().ok_or("--start needs a value")?;
+                    args.start = Some(parse_time(&time).ok_or_else(|| format!("invalid --start time {}", time))?);
+                }
+                "--track" => {
+                    let track = iter.next().ok_or("--track needs a value")?;
+                    args.track = match track.parse() {
+                        Ok(track) if track > 0 => Some(track),
+                        _ => return Err(format!("--track must be a track number, not {}", track)),
+                    };
+                }
+                "--render" => args.render = Some(iter.next().ok_or("--render needs a file name")?.into()),
+                "--channels" => {
+                    let channels = iter.next().ok_or("--channels needs a value")?;
+                    args.channels = match channels.parse() {
+                        Ok(channels) if channels > 0 => Some(channels),
+                        _ => return Err(format!("--channels must be a number of channels, not {}", channels)),
+                    };
+                }
+                "--format" => {
+                    args.sample_format = match iter.next().ok_or("--format needs a value")?.as_str() {
+                        "i16" => Some(cpal::SampleFormat::I16),
+                        "f32" => Some(cpal::SampleFormat::F32),
+                        other => return Err(format!("--format must be i16 or f32, not {}", other)),
+                    };
+                }
+                "--mix" => args.mix_matrix = Some(iter.next().ok_or("--mix needs a value")?.parse()?),
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+                _ => args.files.push(arg),
+            }
         }
+
+        Ok(args)
+    }
+}
+
+fn parse_rate(value: &str) -> Result<SampleRatePolicy, String> {
+    match value {
+        "native" => Ok(SampleRatePolicy::Native),
+        "max" => Ok(SampleRatePolicy::Max),
+        hz => hz
+            .parse()
+            .map(SampleRatePolicy::Fixed)
+            .map_err(|_| format!("--rate must be native, max or a number of Hz, not {}", hz)),
     }
 }
 
-fn write_audio<T: Sample>(data: &mut [T], samples: &mut ringbuf::Consumer<T>, _: &cpal::OutputCallbackInfo) {
-    for d in data {
-        // copy as many samples as we have.
-        // if we run out, write silence
-        match samples.pop() {
-            Some(sample) => *d = sample,
-            None => *d = Sample::from(&0.0)
+// Accepts seconds ("90", "90.5"), "mm:ss" or "hh:mm:ss"
+fn parse_time(value: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    for part in value.split(':') {
+        let part: f64 = part.parse().ok()?;
+        if part < 0.0 {
+            return None;
         }
+        seconds = seconds * 60.0 + part;
     }
+    Some(Duration::from_secs_f64(seconds))
 }
 
-fn init_cpal() -> (cpal::Device, cpal::SupportedStreamConfig) {
-    let device = cpal::default_host()
-        .default_output_device()
-        .expect("no output device available");
+// Print every host, its output devices, and what each device supports
+fn list_devices(host_filter: Option<&str>) -> Result<(), PlayerError> {
+    let hosts = match host_filter {
+        Some(name) => vec![find_host(name)?],
+        None => cpal::available_hosts()
+            .into_iter()
+            .filter_map(|id| cpal::host_from_id(id).ok())
+            .collect(),
+    };
 
-    // Create an output stream for the audio so we can play it
-    // NOTE: If system doesn't support the file's sample rate, the program will panic when we try to play,
-    //       so we'll need to resample the audio to a supported config
-    let supported_config_range = device.supported_output_configs()
-        .expect("error querying audio output configs")
-        .next()
-        .expect("no supported audio config found");
+    for host in hosts {
+        println!("{}", host.id().name());
+        for device in output_devices(&host)? {
+            println!("  {}: {}{}", device.index, device.name, if device.is_default { " (default)" } else { "" });
+            for config in device.configs {
+                println!(
+                    "      {} ch, {}-{} Hz, {:?}",
+                    config.channels(),
+                    config.min_sample_rate().0,
+                    config.max_sample_rate().0,
+                    config.sample_format()
+                );
+            }
+        }
+    }
 
-    // Pick the best (highest) sample rate
-    (device, supported_config_range.with_max_sample_rate())
+    Ok(())
 }
 
-// Interpret the audio frame's data as packed (alternating channels, 12121212, as opposed to planar 11112222)
-pub fn packed<T: frame::audio::Sample>(frame: &frame::Audio) -> &[T] {
-    if !frame.is_packed() {
-        panic!("data is not packed");
+// Read commands from stdin, one per line, and pass them on to the player
+fn spawn_keyboard_controls(controller: Controller, entries: Vec<PlaylistEntry>) {
+    if !std::io::stdin().is_terminal() {
+        return;
     }
 
-    if !<T as frame::audio::Sample>::is_valid(frame.format(), frame.channels()) {
-        panic!("unsupported type");
-    }
+    eprintln!("[enter/p] pause/resume  [g <time>] go to time  [n/b] next/back  [t <n>] go to track");
+    eprintln!("[+/-] volume  [m] mute  [a] audio streams  [a <index>] switch stream  [s] status  [q] quit");
 
-    unsafe { std::slice::from_raw_parts((*frame.as_ptr()).data[0] as *const T, frame.samples() * frame.channels() as usize) }
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+
+            let still_playing = match line.trim() {
+                "" | "p" => controller.toggle_pause(),
+                seek if seek.starts_with("g ") => match parse_time(seek[2..].trim()) {
+                    Some(position) => controller.seek(position),
+                    None => {
+                        eprintln!("invalid time {:?}", &seek[2..]);
+                        true
+                    }
+                },
+                "n" => controller.next_track(),
+                "b" => controller.previous_track(),
+                track if track.starts_with("t ") => match track[2..].trim().parse::<usize>() {
+                    Ok(track) if track > 0 => controller.skip_to(track - 1),
+                    _ => {
+                        eprintln!("invalid track {:?}", &track[2..]);
+                        true
+                    }
+                },
+                "a" => match controller.status() {
+                    Some(status) => {
+                        match audio_stream_lines(&entries[status.track], &StreamSelector::Index(status.audio_stream)) {
+                            Ok(lines) => lines.iter().for_each(|line| eprintln!("{}", line)),
+                            Err(e) => eprintln!("can't list the audio streams: {}", e),
+                        }
+                        true
+                    }
+                    None => false,
+                },
+                stream if stream.starts_with("a ") => match stream[2..].trim().parse::<usize>() {
+                    Ok(index) => controller.select_audio_stream(index),
+                    Err(_) => {
+                        eprintln!("invalid stream index {:?}", &stream[2..]);
+                        true
+                    }
+                },
+                "+" => controller.adjust_volume_db(3.0),
+                "-" => controller.adjust_volume_db(-3.0),
+                "m" => controller.toggle_mute(),
+                "q" => {
+                    controller.stop();
+                    false
+                }
+                "s" => match controller.status() {
+                    Some(status) => {
+                        eprintln!(
+                            "{:?} {} ({}) at {}, volume {:.1} dB{}",
+                            status.state,
+                            status.track + 1,
+                            entries[status.track].name(),
+                            format_time(status.position),
+                            ffmpeg_cpal_play_audio::gain_to_db(status.volume),
+                            if status.muted { " (muted)" } else { "" }
+                        );
+                        true
+                    }
+                    None => false,
+                },
+                other => {
+                    eprintln!("unknown command {:?}", other);
+                    true
+                }
+            };
+
+            if !still_playing {
+                return;
+            }
+        }
+    });
 }
 
-fn main() -> Result<(), ffmpeg::Error> {
-    ffmpeg::init().unwrap();
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
 
-    let file = &std::env::args().nth(1).expect("Cannot open file.");
+// Keep a "mm:ss / mm:ss" line up to date on the terminal, with the track's name above it when it changes
+fn spawn_position_display(controller: Controller, entries: Vec<PlaylistEntry>) {
+    if !std::io::stderr().is_terminal() {
+        return;
+    }
 
-    // Initialize cpal for playing audio
-    let (device, stream_config) = init_cpal();
+    thread::spawn(move || {
+        let mut current = None;
+        let mut stream_title = None;
+        while let Some(status) = controller.status() {
+            if status.state == PlaybackState::Stopped || status.state == PlaybackState::Finished {
+                break;
+            }
 
-    // Open the file
-    let mut ictx = input(&file)?;
+            let entry = &entries[status.track];
+            if current != Some(status.track) {
+                if current.is_some() {
+                    eprintln!();
+                }
+                eprintln!("{}", entry.name());
+                current = Some(status.track);
+            }
 
-    // Find the audio stream and its index
-    let audio = ictx
-        .streams()
-        .best(MediaType::Audio)
-        .ok_or(ffmpeg::Error::StreamNotFound)?;
-    let audio_stream_index = audio.index();
+            // Internet radio says what's on as it changes
+            // (padded to cover up the position line)
+            if let Some(title) = status.stream_title.as_ref() {
+                if stream_title.as_ref() != Some(title) {
+                    eprintln!("\r{:<40}", title);
+                    stream_title = Some(title.clone());
+                }
+            }
 
-    // Create a decoder
-    let mut audio_decoder = audio.codec().decoder().audio()?;
+            // The container's idea of the duration is more reliable than the playlist's
+            let duration = status.duration.or(entry.duration).map(format_time).unwrap_or_else(|| "--:--".to_string());
+            eprint!(
+                "\r[{}] {} / {}{}{}  ",
+                status.track + 1,
+                format_time(status.position),
+                duration,
+                if status.state == PlaybackState::Buffering { " (buffering)" } else { "" },
+                if status.muted { " (muted)" } else { "" }
+            );
+            thread::sleep(Duration::from_millis(250));
+        }
+        eprintln!();
+    });
+}
 
-    // Set up a resampler for the audio
-    let mut resampler = ResamplingContext::get(
-        audio_decoder.format(),
-        audio_decoder.channel_layout(),
-        audio_decoder.rate(),
-        
-        stream_config.sample_format().as_ffmpeg_sample(),
-        audio_decoder.channel_layout(),
-        stream_config.sample_rate().0
-    )?;
+// Exit codes: 1 for anything wrong with the file, 2 for bad arguments, 3 for audio device problems
+fn exit_code(error: &PlayerError) -> i32 {
+    match error {
+        PlayerError::Ffmpeg(_)
+        | PlayerError::UnsupportedFormat(_)
+        | PlayerError::DecoderThread
+        | PlayerError::SinkNotConfigured
+        | PlayerError::EmptyPlaylist
+        | PlayerError::AudioStreamNotFound(_)
+        | PlayerError::Io(_) => 1,
+        PlayerError::MixMatrix { .. } => 2,
+        _ => 3,
+    }
+}
 
-    // A buffer to hold audio samples
-    let buffer = RingBuffer::<f32>::new(8192);
-    let (mut producer, mut consumer) = buffer.split();
-    
-    // Set up the audio output stream
-    let audio_stream = match stream_config.sample_format() {
-        SampleFormat::F32 => device.build_output_stream(&stream_config.into(), move |data: &mut [f32], cbinfo| {
-            // Copy to the audio buffer (if there aren't enough samples, write_audio will write silence)
-            write_audio(data, &mut consumer, &cbinfo)
-        }, |err| {
-            eprintln!("error occurred on the audio output stream: {}", err)
-        }),
-        SampleFormat::I16 => panic!("i16 output format unimplemented"),
-        SampleFormat::U16 => panic!("u16 output format unimplemented")
-    }.unwrap();
+// Something to try next, for errors where there's an obvious next step
+fn hint(error: &PlayerError) -> Option<&'static str> {
+    match error {
+        PlayerError::DeviceNotFound(_) => Some("run with --list-devices to see the available devices"),
+        PlayerError::NoDefaultDevice => Some("pick a device with --device (see --list-devices)"),
+        PlayerError::NoSupportedConfig => Some("try another device with --device (see --list-devices)"),
+        PlayerError::Ffmpeg(ffmpeg::Error::StreamNotFound) => Some("the file doesn't seem to have an audio stream"),
+        PlayerError::Stream(_) => Some("the audio device may have been disconnected"),
+        PlayerError::AudioStreamNotFound(_) => Some("run with --audio-streams to see the file's audio streams"),
+        _ => None,
+    }
+}
+
+// Print what's in each file: one block of text each, or one line of JSON each
+fn print_info(entries: &[PlaylistEntry], selector: &StreamSelector, json: bool) {
+    let mut failed = false;
+    for (i, entry) in entries.iter().enumerate() {
+        let info = match MediaInfo::read_entry_with(entry, selector) {
+            Ok(info) => info,
+            Err(e) => {
+                eprintln!("error: {}: {}", entry.path.display(), e);
+                failed = true;
+                continue;
+            }
+        };
 
-    let mut receive_and_queue_audio_frames =
-        |decoder: &mut ffmpeg::decoder::Audio| -> Result<(), ffmpeg::Error> {
-            let mut decoded = frame::Audio::empty();
+        if json {
+            println!("{}", info.to_json());
+        } else {
+            if i > 0 {
+                println!();
+            }
+            println!("{}", entry.path.display());
+            print!("{}", info);
+        }
+    }
 
-            // Ask the decoder for frames
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                // Resample the frame's audio into another frame
-                let mut resampled = frame::Audio::empty();
-                resampler.run(&decoded, &mut resampled)?;
+    if failed {
+        process::exit(1);
+    }
+}
 
-                // DON'T just use resampled.data(0).len() -- it might not be fully populated
-                // Grab the right number of bytes based on sample count, bytes per sample, and number of channels.
-                let both_channels = packed(&resampled);
+// A line for each of a file's audio streams, marking the one `selector` picks to play
+fn audio_stream_lines(entry: &PlaylistEntry, selector: &StreamSelector) -> Result<Vec<String>, PlayerError> {
+    let info = MediaInfo::read_entry_with(entry, selector)?;
+    Ok(info
+        .audio_streams()
+        .map(|stream| {
+            let marker = if Some(stream.index) == info.audio_stream { " (plays)" } else { "" };
+            format!("  #{}: {}{}", stream.index, stream, marker)
+        })
+        .collect())
+}
 
-                // Sleep until the buffer has enough space for all of the samples
-                // (the producer will happily accept a partial write, which we don't want)
-                while producer.remaining() < both_channels.len() {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
+// Print the audio streams in each file, for picking one with --audio-stream
+fn list_audio_streams(entries: &[PlaylistEntry], selector: &StreamSelector) {
+    let mut failed = false;
+    for entry in entries {
+        match audio_stream_lines(entry, selector) {
+            Ok(lines) => {
+                println!("{}", entry.path.display());
+                for line in lines {
+                    println!("{}", line);
                 }
-
-                // Buffer the samples for playback
-                producer.push_slice(both_channels);
             }
-            Ok(())
-        };
+            Err(e) => {
+                eprintln!("error: {}: {}", entry.path.display(), e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn skip_warning() -> Option<SkipHandler> {
+    Some(Box::new(|path, e| eprintln!("warning: skipping {}: {}", path.display(), e)))
+}
+
+fn mix_fallback_warning() -> Option<SkipHandler> {
+    Some(Box::new(|path, e| eprintln!("\rwarning: --mix doesn't fit {} ({}), using the default mix", path.display(), e)))
+}
 
-    // Start playing
-    audio_stream.play().unwrap();
+fn decode_error_warning(path: &Path, position: Duration, e: &PlayerError) {
+    eprintln!("\rwarning: skipped a bad packet in {} at {}: {}", path.display(), format_time(position), e);
+}
 
-    // The main loop!
-    for (stream, packet) in ictx.packets() {
-        // Look for audio packets (ignore video and others)
-        if stream.index() == audio_stream_index {
-            // Send the packet to the decoder; it will combine them into frames.
-            // In practice though, 1 packet = 1 frame
-            audio_decoder.send_packet(&packet)?;
+fn print_decode_summary(errors: DecodeErrors) {
+    let plural = if errors.bad_packets == 1 { "" } else { "s" };
+    match errors.bad_packets {
+        0 => {}
+        count if errors.concealed > Duration::from_secs(0) => eprintln!(
+            "warning: skipped {} bad packet{}, replaced with {:.1}s of silence",
+            count,
+            plural,
+            errors.concealed.as_secs_f64()
+        ),
+        count => eprintln!("warning: skipped {} bad packet{}", count, plural),
+    }
+}
 
-            // Queue the audio for playback (and block if the queue is full)
-            receive_and_queue_audio_frames(&mut audio_decoder)?;
+// Write everything to a WAV file instead of playing it
+fn render(args: &Args, entries: Vec<PlaylistEntry>, output: &Path) -> Result<(), PlayerError> {
+    let sample_rate = match args.sample_rate {
+        SampleRatePolicy::Native => None,
+        SampleRatePolicy::Fixed(rate) => Some(rate),
+        SampleRatePolicy::Max => {
+            eprintln!("--rate max needs a device; give a rate in Hz to render at");
+            process::exit(2);
         }
+    };
+    if args.track.is_some() || args.start.is_some() {
+        eprintln!("--track and --start can't be used with --render");
+        process::exit(2);
     }
 
+    let mut options = RenderOptions {
+        sample_rate,
+        channels: args.channels,
+        mix_matrix: args.mix_matrix.clone(),
+        audio_stream: args.audio_stream.clone(),
+        on_skip: skip_warning(),
+        strict: args.strict,
+        on_decode_error: Some(Box::new(decode_error_warning)),
+        on_mix_fallback: mix_fallback_warning(),
+        ..RenderOptions::default()
+    };
+    if let Some(sample_format) = args.sample_format {
+        options.sample_format = sample_format;
+    }
+
+    let (frames, errors) = render_to_wav(entries, output, options)?;
+    print_decode_summary(errors);
+    eprintln!("wrote {} frames to {}", frames, output.display());
     Ok(())
-}
\ No newline at end of file
+}
+
+fn run(args: Args) -> Result<(), PlayerError> {
+    if args.list_devices {
+        return list_devices(args.host.as_deref());
+    }
+
+    if args.files.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let entries = expand_paths(&args.files, |path, e| eprintln!("warning: skipping {}: {}", path.display(), e))?;
+
+    if args.info {
+        print_info(&entries, &args.audio_stream, args.json);
+        return Ok(());
+    }
+    if args.list_audio_streams {
+        list_audio_streams(&entries, &args.audio_stream);
+        return Ok(());
+    }
+    if let Some(output) = args.render.as_ref() {
+        return render(&args, entries, output);
+    }
+
+    let device = select_device(args.host.as_deref(), args.device.as_deref())?;
+
+    let mut options = PlayerOptions {
+        device: Some(device),
+        sample_rate: args.sample_rate,
+        mix_matrix: args.mix_matrix,
+        audio_stream: args.audio_stream,
+        on_skip: skip_warning(),
+        strict: args.strict,
+        on_decode_error: Some(Box::new(decode_error_warning)),
+        on_mix_fallback: mix_fallback_warning(),
+        ..PlayerOptions::default()
+    };
+    if let Some(buffer_ms) = args.buffer_ms {
+        options.buffer_ms = buffer_ms;
+    }
+    // Give streams a head start so a slow connection doesn't mean stuttering
+    let streaming = entries.iter().any(|entry| is_url(&entry.path));
+    options.prebuffer_ms = args.prebuffer_ms.unwrap_or(if streaming { 2000 } else { 0 });
+    let mut player = Player::open_entries(entries.clone(), options)?;
+
+    if let Some(track) = args.track {
+        player.skip_to(track - 1);
+    }
+    if let Some(start) = args.start {
+        player.seek(start);
+    }
+
+    // Commands come in on stdin too, so they're off when it's carrying audio
+    if !args.files.iter().any(|file| file == "-") {
+        spawn_keyboard_controls(player.controller(), entries.clone());
+    }
+    spawn_position_display(player.controller(), entries);
+
+    // Start playing, and block until every file has been played (or we're told to quit)
+    player.play()?;
+    let result = player.wait();
+    print_decode_summary(player.decode_errors());
+    result
+}
+
+fn main() {
+    let args = Args::parse().unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(2);
+    });
+
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        if let Some(hint) = hint(&e) {
+            eprintln!("hint: {}", hint);
+        }
+        process::exit(exit_code(&e));
+    }
+}
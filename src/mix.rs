@@ -0,0 +1,199 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+use std::str::FromStr;
+
+use ffmpeg::software::resampling::context::Context as ResamplingContext;
+use ffmpeg::ChannelLayout;
+
+use crate::error::PlayerError;
+
+/// Weights for mixing input channels into output channels, one row per output channel.
+///
+/// Channels are in ffmpeg's order for the layout (FL, FR, FC, LFE, BL, BR, ... SL, SR).
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixMatrix {
+    inputs: usize,
+    outputs: usize,
+    coefficients: Vec<f64>,
+}
+
+impl MixMatrix {
+    /// Build a matrix from rows of input channel weights. Returns None if the rows aren't all the same length.
+    pub fn new(rows: &[&[f64]]) -> Option<MixMatrix> {
+        let inputs = rows.first()?.len();
+        if inputs == 0 || rows.iter().any(|row| row.len() != inputs) {
+            return None;
+        }
+
+        Some(MixMatrix {
+            inputs,
+            outputs: rows.len(),
+            coefficients: rows.iter().flat_map(|row| row.iter().copied()).collect(),
+        })
+    }
+
+    /// The built-in matrix for going from `input` to `output`, if there is one.
+    ///
+    /// For anything not covered here swresample's own rematrixing is used.
+    pub fn for_layouts(input: ChannelLayout, output: ChannelLayout) -> Option<MixMatrix> {
+        if input == output {
+            return None;
+        }
+
+        if input == ChannelLayout::MONO && output == ChannelLayout::STEREO {
+            // Same signal in both speakers
+            MixMatrix::new(&[&[1.0], &[1.0]])
+        } else if input == ChannelLayout::STEREO && output == ChannelLayout::MONO {
+            MixMatrix::new(&[&[0.5, 0.5]])
+        } else if (input == ChannelLayout::_5POINT1 || input == ChannelLayout::_5POINT1_BACK)
+            && output == ChannelLayout::STEREO
+        {
+            // ITU-R BS.775: centre and surrounds at -3dB, LFE dropped. Each row adds up to about 2.4,
+            // and swresample doesn't normalise a matrix it's given, so scale it down to keep from clipping.
+            MixMatrix::new(&[
+                &[1.0, 0.0, FRAC_1_SQRT_2, 0.0, FRAC_1_SQRT_2, 0.0],
+                &[0.0, 1.0, FRAC_1_SQRT_2, 0.0, 0.0, FRAC_1_SQRT_2],
+            ])
+            .map(MixMatrix::normalized)
+        } else {
+            None
+        }
+    }
+
+    // Scale every weight down so that no output channel can go over full scale (the largest row adds up to 1)
+    fn normalized(mut self) -> MixMatrix {
+        let loudest = self
+            .coefficients
+            .chunks(self.inputs)
+            .map(|row| row.iter().map(|weight| weight.abs()).sum::<f64>())
+            .fold(0.0, f64::max);
+        if loudest > 1.0 {
+            self.coefficients.iter_mut().for_each(|weight| *weight /= loudest);
+        }
+        self
+    }
+
+    // Whether this matrix mixes `input`'s channels into `output`'s
+    pub(crate) fn fits(&self, input: ChannelLayout, output: ChannelLayout) -> bool {
+        self.inputs == input.channels() as usize && self.outputs == output.channels() as usize
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+}
+
+/// Parses rows separated by `;` of weights separated by `,`, e.g. `"1,0,0.7;0,1,0.7"`.
+impl FromStr for MixMatrix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rows = s
+            .split(';')
+            .map(|row| row.split(',').map(|w| w.trim().parse::<f64>()).collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid mix matrix weight: {}", e))?;
+
+        let rows: Vec<&[f64]> = rows.iter().map(|row| row.as_slice()).collect();
+        MixMatrix::new(&rows).ok_or_else(|| "every row of the mix matrix needs the same number of weights".to_string())
+    }
+}
+
+/// The layout we ask the resampler for on a device with `channels` outputs.
+pub fn output_layout(channels: u16) -> ChannelLayout {
+    ChannelLayout::default(i32::from(channels))
+}
+
+// Swap the resampler's matrix for `matrix`. swresample only takes a custom matrix
+// before it's initialized, so close it, set the matrix, and initialize it again.
+pub(crate) fn set_matrix(resampler: &mut ResamplingContext, matrix: &MixMatrix) -> Result<(), PlayerError> {
+    let inputs = resampler.input().channel_layout.channels() as usize;
+    let outputs = resampler.output().channel_layout.channels() as usize;
+    if matrix.inputs != inputs || matrix.outputs != outputs {
+        return Err(PlayerError::MixMatrix { inputs, outputs });
+    }
+
+    unsafe {
+        let ctx = resampler.as_mut_ptr();
+        ffmpeg::ffi::swr_close(ctx);
+
+        match ffmpeg::ffi::swr_set_matrix(ctx, matrix.coefficients.as_ptr(), matrix.inputs as i32) {
+            0 => {}
+            e => return Err(ffmpeg::Error::from(e).into()),
+        }
+
+        match ffmpeg::ffi::swr_init(ctx) {
+            0 => Ok(()),
+            e => Err(ffmpeg::Error::from(e).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What each output channel's weights add up to
+    fn row_sums(matrix: &MixMatrix) -> Vec<f64> {
+        matrix.coefficients.chunks(matrix.inputs).map(|row| row.iter().sum()).collect()
+    }
+
+    #[test]
+    fn parses_rows_and_weights() {
+        let matrix: MixMatrix = "1, 0, 0.7; 0, 1, 0.7".parse().unwrap();
+        assert_eq!(matrix, MixMatrix::new(&[&[1.0, 0.0, 0.7], &[0.0, 1.0, 0.7]]).unwrap());
+        assert_eq!((matrix.inputs(), matrix.outputs()), (3, 2));
+    }
+
+    #[test]
+    fn rejects_bad_weights_and_ragged_rows() {
+        assert!("1,0;x,1".parse::<MixMatrix>().is_err());
+        assert!("1,0;1".parse::<MixMatrix>().is_err());
+        assert!("".parse::<MixMatrix>().is_err());
+    }
+
+    #[test]
+    fn new_needs_equal_rows_with_something_in_them() {
+        assert_eq!(MixMatrix::new(&[&[1.0, 0.0], &[1.0]]), None);
+        assert_eq!(MixMatrix::new(&[]), None);
+        assert_eq!(MixMatrix::new(&[&[], &[]]), None);
+    }
+
+    #[test]
+    fn normalizing_keeps_rows_within_full_scale() {
+        let loud = MixMatrix::new(&[&[1.0, 1.0, 2.0], &[0.5, 0.5, 0.0]]).unwrap().normalized();
+        assert_eq!(row_sums(&loud), vec![1.0, 0.25]);
+
+        // Nothing to do for a matrix that can't clip
+        let quiet = MixMatrix::new(&[&[0.5, 0.5]]).unwrap();
+        assert_eq!(quiet.clone().normalized(), quiet);
+    }
+
+    #[test]
+    fn mono_goes_to_both_speakers() {
+        let matrix = MixMatrix::for_layouts(ChannelLayout::MONO, ChannelLayout::STEREO).unwrap();
+        assert_eq!(matrix, MixMatrix::new(&[&[1.0], &[1.0]]).unwrap());
+    }
+
+    #[test]
+    fn surround_folds_down_without_clipping() {
+        for input in [ChannelLayout::_5POINT1, ChannelLayout::_5POINT1_BACK].iter() {
+            let matrix = MixMatrix::for_layouts(*input, ChannelLayout::STEREO).unwrap();
+            assert_eq!((matrix.inputs(), matrix.outputs()), (6, 2));
+            for sum in row_sums(&matrix) {
+                assert!((sum - 1.0).abs() < 1e-9, "row adds up to {}", sum);
+            }
+            // The LFE is dropped
+            assert_eq!((matrix.coefficients[3], matrix.coefficients[9]), (0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn same_layout_needs_no_matrix() {
+        assert_eq!(MixMatrix::for_layouts(ChannelLayout::STEREO, ChannelLayout::STEREO), None);
+        assert_eq!(MixMatrix::for_layouts(ChannelLayout::_5POINT1, ChannelLayout::_5POINT1), None);
+    }
+}
@@ -0,0 +1,97 @@
+use std::ffi::{c_void, CStr};
+use std::path::Path;
+use std::ptr;
+
+use ffmpeg::format::context::Input;
+use ffmpeg::{ffi, Dictionary};
+
+/// Whether `path` is something ffmpeg fetches over the network (http, https, rtmp...) rather than a file.
+pub fn is_url(path: &Path) -> bool {
+    match path.to_str() {
+        Some(path) => path.contains("://") && !path.starts_with("file://"),
+        None => false,
+    }
+}
+
+// Options for ffmpeg's http protocol. It reconnects by itself when a connection drops, and asks
+// Icecast/Shoutcast servers to send ICY metadata (the title of what's playing) along with the audio.
+pub(crate) fn open_options() -> Dictionary<'static> {
+    let mut options = Dictionary::new();
+    options.set("reconnect", "1");
+    options.set("reconnect_streamed", "1");
+    options.set("reconnect_on_network_error", "1");
+    options.set("reconnect_delay_max", "5");
+    options.set("icy", "1");
+    options
+}
+
+// The title in the last ICY metadata the server sent, if it's sent any
+pub(crate) fn icy_stream_title(ictx: &Input) -> Option<String> {
+    unsafe {
+        let pb = (*ictx.as_ptr()).pb;
+        if pb.is_null() {
+            return None;
+        }
+
+        let mut value: *mut u8 = ptr::null_mut();
+        let name = b"icy_metadata_packet\0".as_ptr() as *const _;
+        let found = ffi::av_opt_get(pb as *mut c_void, name, ffi::AV_OPT_SEARCH_CHILDREN as i32, &mut value);
+        if found < 0 || value.is_null() {
+            return None;
+        }
+        let packet = CStr::from_ptr(value as *const _).to_string_lossy().into_owned();
+        ffi::av_free(value as *mut c_void);
+
+        parse_icy_title(&packet)
+    }
+}
+
+/// Pull the title out of an ICY metadata packet, like `StreamTitle='Artist - Title';StreamUrl='';`.
+pub fn parse_icy_title(packet: &str) -> Option<String> {
+    let start = packet.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &packet[start..];
+
+    // Titles can have quotes in them, so it ends at the first "';" rather than the first quote
+    let title = match rest.find("';") {
+        Some(end) => &rest[..end],
+        None => rest.trim_end_matches(&[';', '\''][..]),
+    };
+    let title = title.trim();
+    if title.is_empty() {
+        None
+    } else {
+        Some(title.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_title() {
+        let packet = "StreamTitle='Artist - Title';StreamUrl='';";
+        assert_eq!(parse_icy_title(packet), Some("Artist - Title".to_string()));
+    }
+
+    #[test]
+    fn keeps_quotes_inside_the_title() {
+        let packet = "StreamTitle='Don't Stop Me Now';StreamUrl='http://example.com/';";
+        assert_eq!(parse_icy_title(packet), Some("Don't Stop Me Now".to_string()));
+    }
+
+    #[test]
+    fn reads_a_title_without_the_closing_quote() {
+        assert_eq!(parse_icy_title("StreamTitle='Artist - Title"), Some("Artist - Title".to_string()));
+        assert_eq!(parse_icy_title("StreamTitle='Artist - Title';"), Some("Artist - Title".to_string()));
+        assert_eq!(parse_icy_title("StreamTitle='Artist - Title'"), Some("Artist - Title".to_string()));
+    }
+
+    #[test]
+    fn empty_titles_are_none() {
+        assert_eq!(parse_icy_title("StreamTitle='';"), None);
+        assert_eq!(parse_icy_title("StreamTitle='  ';StreamUrl='';"), None);
+        assert_eq!(parse_icy_title("StreamUrl='http://example.com/';"), None);
+        assert_eq!(parse_icy_title(""), None);
+    }
+}
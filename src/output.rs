@@ -0,0 +1,448 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Sample, SampleFormat};
+use ffmpeg::format::sample::Type as SampleType;
+use ffmpeg::format::Sample as FFmpegSample;
+use ffmpeg::frame;
+
+use crate::buffer::SampleConsumer;
+use crate::decode::StreamInfo;
+use crate::error::PlayerError;
+use crate::sink::{AudioSink, ErrorCallback, SampleCallback, SinkCallback, SinkConfig};
+
+pub trait SampleFormatConversion {
+    fn as_ffmpeg_sample(&self) -> FFmpegSample;
+}
+
+impl SampleFormatConversion for SampleFormat {
+    fn as_ffmpeg_sample(&self) -> FFmpegSample {
+        match self {
+            Self::I16 => FFmpegSample::I16(SampleType::Packed),
+            // The ffmpeg resampler doesn't support u16, so resample to i16 and convert afterwards
+            Self::U16 => FFmpegSample::I16(SampleType::Packed),
+            Self::F32 => FFmpegSample::F32(SampleType::Packed)
+        }
+    }
+}
+
+/// A sample type that can be sent to a cpal output stream.
+///
+/// `Resampled` is the type ffmpeg produces for it. Usually that's the same type, but
+/// swresample has no u16 support so u16 output is resampled as i16 and converted.
+pub trait OutputSample: Sample + Send + 'static {
+    type Resampled: frame::audio::Sample + Sample;
+
+    fn from_resampled(sample: &Self::Resampled) -> Self {
+        Sample::from(sample)
+    }
+
+    // Wrap a callback in this sample type up for an AudioSink
+    fn sink_callback(callback: SampleCallback<Self>) -> SinkCallback;
+}
+
+impl OutputSample for f32 {
+    type Resampled = f32;
+
+    fn sink_callback(callback: SampleCallback<Self>) -> SinkCallback {
+        SinkCallback::F32(callback)
+    }
+}
+
+impl OutputSample for i16 {
+    type Resampled = i16;
+
+    fn sink_callback(callback: SampleCallback<Self>) -> SinkCallback {
+        SinkCallback::I16(callback)
+    }
+}
+
+impl OutputSample for u16 {
+    type Resampled = i16;
+
+    fn sink_callback(callback: SampleCallback<Self>) -> SinkCallback {
+        SinkCallback::U16(callback)
+    }
+}
+
+// Returns how many samples came from the buffer (the rest of `data` is silence)
+pub fn write_audio<T: Sample>(data: &mut [T], samples: &mut SampleConsumer<T>) -> usize {
+    // Skip anything left over from before a seek
+    samples.discard_stale();
+
+    let mut written = 0;
+    for d in data {
+        // copy as many samples as we have.
+        // if we run out, write silence
+        match samples.pop() {
+            Some(sample) => {
+                *d = sample;
+                written += 1;
+            }
+            None => *d = Sample::from(&0.0)
+        }
+    }
+
+    // Wake the decoder if it's waiting for room in the buffer
+    samples.notify();
+    written
+}
+
+/// How to choose the output sample rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleRatePolicy {
+    /// Use the file's own rate when the device supports it, so nothing needs resampling.
+    #[default]
+    Native,
+    /// Use the highest rate the device supports.
+    Max,
+    /// Use this rate (or the closest the device supports).
+    Fixed(u32),
+}
+
+pub fn init_cpal(
+    device: Option<cpal::Device>,
+    info: &StreamInfo,
+    policy: SampleRatePolicy,
+) -> Result<(cpal::Device, cpal::SupportedStreamConfig), PlayerError> {
+    let device = match device {
+        Some(device) => device,
+        None => cpal::default_host()
+            .default_output_device()
+            .ok_or(PlayerError::NoDefaultDevice)?,
+    };
+
+    // Create an output stream for the audio so we can play it. Whatever we pick here,
+    // the resampler will convert the file's audio to match.
+    let supported_config_ranges: Vec<_> = device.supported_output_configs()?.collect();
+
+    let config = negotiate_config(&supported_config_ranges, info, policy)
+        .ok_or(PlayerError::NoSupportedConfig)?;
+
+    Ok((device, config))
+}
+
+/// Plays through a cpal output device. This is the sink `Player::open` uses.
+pub struct CpalSink {
+    device: Option<cpal::Device>,
+    policy: SampleRatePolicy,
+    config: Option<cpal::SupportedStreamConfig>,
+    stream: Option<cpal::Stream>,
+    // The latency the output callback last saw, for latency()
+    latency_micros: Arc<AtomicU64>,
+}
+
+impl CpalSink {
+    /// Play on `device`, or the default host's default output device if that's None.
+    pub fn new(device: Option<cpal::Device>, policy: SampleRatePolicy) -> CpalSink {
+        CpalSink {
+            device,
+            policy,
+            config: None,
+            stream: None,
+            latency_micros: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn build_stream<T: Sample + 'static>(
+        &self,
+        device: &cpal::Device,
+        config: &cpal::SupportedStreamConfig,
+        mut callback: SampleCallback<T>,
+        mut on_error: ErrorCallback,
+    ) -> Result<cpal::Stream, PlayerError> {
+        let latency_micros = self.latency_micros.clone();
+        let stream = device.build_output_stream(&config.config(), move |data: &mut [T], info| {
+            // How long until what we write now comes out of the speakers
+            let timestamp = info.timestamp();
+            let latency = timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default();
+            latency_micros.store(latency.as_micros() as u64, Ordering::Relaxed);
+
+            callback(data, latency);
+        }, move |err| on_error(err.into()))?;
+        Ok(stream)
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn configure(&mut self, info: &StreamInfo) -> Result<SinkConfig, PlayerError> {
+        // Pick a config that suits the file
+        let (device, config) = init_cpal(self.device.take(), info, self.policy)?;
+        let sink_config = SinkConfig {
+            channels: config.channels(),
+            sample_rate: config.sample_rate().0,
+            sample_format: config.sample_format(),
+        };
+        self.device = Some(device);
+        self.config = Some(config);
+        Ok(sink_config)
+    }
+
+    fn start(&mut self, callback: SinkCallback, on_error: ErrorCallback) -> Result<(), PlayerError> {
+        let (device, config) = match (self.device.as_ref(), self.config.as_ref()) {
+            (Some(device), Some(config)) => (device, config),
+            _ => return Err(PlayerError::SinkNotConfigured),
+        };
+
+        let stream = match callback {
+            SinkCallback::F32(callback) => self.build_stream(device, config, callback, on_error)?,
+            SinkCallback::I16(callback) => self.build_stream(device, config, callback, on_error)?,
+            SinkCallback::U16(callback) => self.build_stream(device, config, callback, on_error)?,
+        };
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn play(&self) -> Result<(), PlayerError> {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.play()?;
+        }
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<(), PlayerError> {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.pause()?;
+        }
+        Ok(())
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Pick the supported config that needs the least conversion of the decoded stream.
+///
+/// In order of importance: a sample rate that satisfies `policy` exactly, a matching channel
+/// count (or failing that, more channels rather than fewer), then F32 over I16 over U16.
+pub fn negotiate_config(
+    ranges: &[cpal::SupportedStreamConfigRange],
+    info: &StreamInfo,
+    policy: SampleRatePolicy,
+) -> Option<cpal::SupportedStreamConfig> {
+    let summaries: Vec<_> = ranges.iter().map(RangeSummary::of).collect();
+    let (index, rate) = best_range(&summaries, info, policy)?;
+    Some(ranges[index].clone().with_sample_rate(cpal::SampleRate(rate)))
+}
+
+// The parts of a supported config range that negotiate_config looks at. cpal has no way to make a
+// range outside the crate, so the choosing happens on these instead.
+#[derive(Clone, Copy, Debug)]
+struct RangeSummary {
+    channels: u16,
+    min_rate: u32,
+    max_rate: u32,
+    format: SampleFormat,
+}
+
+impl RangeSummary {
+    fn of(range: &cpal::SupportedStreamConfigRange) -> RangeSummary {
+        RangeSummary {
+            channels: range.channels(),
+            min_rate: range.min_sample_rate().0,
+            max_rate: range.max_sample_rate().0,
+            format: range.sample_format(),
+        }
+    }
+}
+
+// The index of the best range for `info`, and the sample rate to use with it
+fn best_range(ranges: &[RangeSummary], info: &StreamInfo, policy: SampleRatePolicy) -> Option<(usize, u32)> {
+    ranges
+        .iter()
+        .enumerate()
+        .map(|(index, range)| {
+            let wanted = match policy {
+                SampleRatePolicy::Native => info.rate,
+                SampleRatePolicy::Max => range.max_rate,
+                SampleRatePolicy::Fixed(rate) => rate,
+            };
+            let rate = wanted.max(range.min_rate).min(range.max_rate);
+
+            let channel_score = if range.channels == info.channels {
+                2
+            } else if range.channels > info.channels {
+                1
+            } else {
+                0
+            };
+
+            let format_score = match range.format {
+                SampleFormat::F32 => 2,
+                SampleFormat::I16 => 1,
+                SampleFormat::U16 => 0,
+            };
+
+            // With the max policy every range gets its own max rate, so prefer the highest of those
+            let distance = match policy {
+                SampleRatePolicy::Max => u32::MAX - rate,
+                _ => (i64::from(rate) - i64::from(wanted)).unsigned_abs() as u32,
+            };
+
+            let score = (rate == wanted, channel_score, format_score, std::cmp::Reverse(distance));
+            (score, (index, rate))
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, choice)| choice)
+}
+
+/// An output device as reported by `output_devices`.
+pub struct OutputDeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<cpal::SupportedStreamConfigRange>,
+}
+
+/// Look up an audio host (alsa, jack, wasapi, coreaudio...) by name, ignoring case.
+pub fn find_host(name: &str) -> Result<cpal::Host, PlayerError> {
+    cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .and_then(|id| cpal::host_from_id(id).ok())
+        .ok_or_else(|| PlayerError::HostNotFound(name.to_string()))
+}
+
+/// Find an output device on `host`, either by its index in `output_devices` or by name.
+///
+/// Names are matched exactly first, then as a case-insensitive substring.
+pub fn find_device(host: &cpal::Host, selector: &str) -> Result<cpal::Device, PlayerError> {
+    let devices: Vec<_> = host.output_devices()?.collect();
+
+    if let Ok(index) = selector.parse::<usize>() {
+        return devices
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| PlayerError::DeviceNotFound(selector.to_string()));
+    }
+
+    let names: Vec<String> = devices.iter().map(|d| d.name().unwrap_or_default()).collect();
+    let needle = selector.to_lowercase();
+    let position = names
+        .iter()
+        .position(|name| name == selector)
+        .or_else(|| names.iter().position(|name| name.to_lowercase().contains(&needle)));
+
+    match position {
+        Some(i) => Ok(devices.into_iter().nth(i).unwrap()),
+        None => Err(PlayerError::DeviceNotFound(selector.to_string())),
+    }
+}
+
+/// Pick the output device to play on: `selector` on `host` if given, otherwise the host's default.
+pub fn select_device(host: Option<&str>, selector: Option<&str>) -> Result<cpal::Device, PlayerError> {
+    let host = match host {
+        Some(name) => find_host(name)?,
+        None => cpal::default_host(),
+    };
+
+    match selector {
+        Some(selector) => find_device(&host, selector),
+        None => host.default_output_device().ok_or(PlayerError::NoDefaultDevice),
+    }
+}
+
+/// Every output device on `host`, along with the configs it supports.
+pub fn output_devices(host: &cpal::Host) -> Result<Vec<OutputDeviceInfo>, PlayerError> {
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+
+    let devices = host
+        .output_devices()?
+        .enumerate()
+        .map(|(index, device)| {
+            let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+            OutputDeviceInfo {
+                index,
+                is_default: default_name.as_ref() == Some(&name),
+                configs: device
+                    .supported_output_configs()
+                    .map(|configs| configs.collect())
+                    .unwrap_or_default(),
+                name,
+            }
+        })
+        .collect();
+
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(channels: u16, min_rate: u32, max_rate: u32, format: SampleFormat) -> RangeSummary {
+        RangeSummary {
+            channels,
+            min_rate,
+            max_rate,
+            format,
+        }
+    }
+
+    fn stereo_at(rate: u32) -> StreamInfo {
+        StreamInfo {
+            rate,
+            channels: 2,
+            format: FFmpegSample::F32(SampleType::Planar),
+            duration: None,
+        }
+    }
+
+    #[test]
+    fn native_rate_in_range_wins_over_max() {
+        let ranges = [range(2, 8000, 192000, SampleFormat::F32)];
+        assert_eq!(best_range(&ranges, &stereo_at(44100), SampleRatePolicy::Native), Some((0, 44100)));
+    }
+
+    #[test]
+    fn native_rate_beats_a_better_format() {
+        let ranges = [range(2, 48000, 48000, SampleFormat::F32), range(2, 44100, 48000, SampleFormat::I16)];
+        assert_eq!(best_range(&ranges, &stereo_at(44100), SampleRatePolicy::Native), Some((1, 44100)));
+    }
+
+    #[test]
+    fn matching_channels_beat_a_better_format() {
+        let ranges = [range(6, 44100, 48000, SampleFormat::F32), range(2, 44100, 48000, SampleFormat::I16)];
+        assert_eq!(best_range(&ranges, &stereo_at(48000), SampleRatePolicy::Native), Some((1, 48000)));
+    }
+
+    #[test]
+    fn more_channels_beat_fewer() {
+        let ranges = [range(1, 44100, 48000, SampleFormat::F32), range(6, 44100, 48000, SampleFormat::F32)];
+        assert_eq!(best_range(&ranges, &stereo_at(48000), SampleRatePolicy::Native), Some((1, 48000)));
+    }
+
+    #[test]
+    fn f32_beats_i16_beats_u16() {
+        let ranges = [
+            range(2, 44100, 48000, SampleFormat::U16),
+            range(2, 44100, 48000, SampleFormat::F32),
+            range(2, 44100, 48000, SampleFormat::I16),
+        ];
+        assert_eq!(best_range(&ranges, &stereo_at(48000), SampleRatePolicy::Native), Some((1, 48000)));
+        assert_eq!(best_range(&ranges[..1], &stereo_at(48000), SampleRatePolicy::Native), Some((0, 48000)));
+        assert_eq!(best_range(&[ranges[0], ranges[2]], &stereo_at(48000), SampleRatePolicy::Native), Some((1, 48000)));
+    }
+
+    #[test]
+    fn fixed_rate_outside_every_range_is_clamped_to_the_closest() {
+        let ranges = [range(2, 8000, 22050, SampleFormat::F32), range(2, 44100, 48000, SampleFormat::F32)];
+        assert_eq!(best_range(&ranges, &stereo_at(44100), SampleRatePolicy::Fixed(96000)), Some((1, 48000)));
+        assert_eq!(best_range(&ranges, &stereo_at(44100), SampleRatePolicy::Fixed(4000)), Some((0, 8000)));
+        assert_eq!(best_range(&ranges, &stereo_at(44100), SampleRatePolicy::Fixed(32000)), Some((0, 22050)));
+    }
+
+    #[test]
+    fn max_picks_the_highest_rate() {
+        let ranges = [range(2, 8000, 48000, SampleFormat::F32), range(2, 8000, 96000, SampleFormat::F32)];
+        assert_eq!(best_range(&ranges, &stereo_at(44100), SampleRatePolicy::Max), Some((1, 96000)));
+    }
+
+    #[test]
+    fn nothing_supported_is_none() {
+        assert_eq!(best_range(&[], &stereo_at(44100), SampleRatePolicy::Native), None);
+    }
+}
@@ -0,0 +1,976 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::Sample;
+use ffmpeg::software::resampling::context::{Context as ResamplingContext, Definition};
+use ffmpeg::{frame, ChannelLayout, Packet, Rescale};
+
+use crate::buffer::SampleProducer;
+use crate::clock::{Anchor, Clock};
+use crate::decode::{interleave, packed, planar, Source, StreamInfo, StreamSelector};
+use crate::error::PlayerError;
+use crate::mix::{output_layout, set_matrix, MixMatrix};
+use crate::network::icy_stream_title;
+use crate::output::{OutputSample, SampleFormatConversion};
+use crate::player::{DecodeErrorHandler, SkipHandler};
+use crate::playlist::PlaylistEntry;
+use crate::sink::SinkConfig;
+
+const NO_SEEK: u64 = u64::MAX;
+const NO_SKIP: usize = usize::MAX;
+const NO_SWITCH: usize = usize::MAX;
+
+// How many times to try reopening a stream after its connection drops, beyond what ffmpeg
+// already tries by itself. The wait doubles each time, starting from RECONNECT_DELAY.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+// The longest gap that's filled with silence after a bad packet. A timestamp that jumps further
+// than this is more likely garbage than a real gap.
+const MAX_CONCEALMENT: Duration = Duration::from_secs(10);
+
+// Flags shared between the player, the decoder thread and the output callback
+pub(crate) struct State {
+    // Set by the player to make the decoder thread give up early
+    pub(crate) stopped: AtomicBool,
+    // Set while the player is paused, which also holds up the decoder thread
+    pub(crate) paused: AtomicBool,
+    // Set once play() has been called
+    pub(crate) started: AtomicBool,
+    // Set by the decoder thread once every sample of the file is in the ring buffer
+    pub(crate) finished: AtomicBool,
+    // Set by the output callback when it runs out of samples after the decoder finished
+    pub(crate) drained: AtomicBool,
+    // Set while the output callback is holding off until the buffer fills up (before playing
+    // a stream, or after running out of audio part way through one)
+    pub(crate) buffering: AtomicBool,
+    // Where the player wants the decoder to seek to, in microseconds (NO_SEEK if nowhere)
+    seek_to: AtomicU64,
+    // Which track the player wants the decoder to skip to (NO_SKIP if none)
+    skip_to: AtomicUsize,
+    // Which audio stream the player wants the decoder to switch to (NO_SWITCH if none)
+    switch_stream: AtomicUsize,
+    // The index of the audio stream being decoded, in its file
+    pub(crate) audio_stream: AtomicUsize,
+    // The title from the stream's ICY metadata, for internet radio
+    pub(crate) stream_title: Mutex<Option<String>>,
+    // How many packets the decoder has rejected, and how much silence (in microseconds) went in their place
+    pub(crate) bad_packets: AtomicU64,
+    pub(crate) concealed: AtomicU64,
+    pub(crate) clock: Clock,
+}
+
+impl State {
+    pub(crate) fn new(clock: Clock) -> State {
+        State {
+            stopped: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            started: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            drained: AtomicBool::new(false),
+            buffering: AtomicBool::new(false),
+            seek_to: AtomicU64::new(NO_SEEK),
+            skip_to: AtomicUsize::new(NO_SKIP),
+            switch_stream: AtomicUsize::new(NO_SWITCH),
+            audio_stream: AtomicUsize::new(0),
+            stream_title: Mutex::new(None),
+            bad_packets: AtomicU64::new(0),
+            concealed: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    pub(crate) fn request_seek(&self, position: Duration) {
+        let micros = (position.as_micros() as u64).min(NO_SEEK - 1);
+        self.seek_to.store(micros, Ordering::SeqCst);
+    }
+
+    pub(crate) fn request_skip(&self, track: usize) {
+        self.skip_to.store(track.min(NO_SKIP - 1), Ordering::SeqCst);
+    }
+
+    pub(crate) fn request_stream_switch(&self, index: usize) {
+        self.switch_stream.store(index.min(NO_SWITCH - 1), Ordering::SeqCst);
+    }
+
+    // Whether the player wants the decoder to seek, skip or switch streams
+    fn jump_pending(&self) -> bool {
+        self.seek_to.load(Ordering::SeqCst) != NO_SEEK
+            || self.skip_to.load(Ordering::SeqCst) != NO_SKIP
+            || self.switch_stream.load(Ordering::SeqCst) != NO_SWITCH
+    }
+
+    fn take_seek(&self) -> Option<Duration> {
+        match self.seek_to.swap(NO_SEEK, Ordering::SeqCst) {
+            NO_SEEK => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    fn take_stream_switch(&self) -> Option<usize> {
+        match self.switch_stream.swap(NO_SWITCH, Ordering::SeqCst) {
+            NO_SWITCH => None,
+            index => Some(index),
+        }
+    }
+
+    fn take_skip(&self) -> Option<usize> {
+        match self.skip_to.swap(NO_SKIP, Ordering::SeqCst) {
+            NO_SKIP => None,
+            track => Some(track),
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+// The tracks to play, in order
+pub(crate) struct Tracks {
+    entries: Vec<PlaylistEntry>,
+    next: usize,
+    // Which audio stream to play from each file
+    selector: StreamSelector,
+    on_skip: Option<SkipHandler>,
+    // Stop at the first packet that won't decode, rather than skipping it
+    strict: bool,
+    on_decode_error: Option<DecodeErrorHandler>,
+    on_mix_fallback: Option<SkipHandler>,
+}
+
+impl Tracks {
+    pub(crate) fn new(
+        entries: Vec<PlaylistEntry>,
+        selector: StreamSelector,
+        on_skip: Option<SkipHandler>,
+        strict: bool,
+        on_decode_error: Option<DecodeErrorHandler>,
+        on_mix_fallback: Option<SkipHandler>,
+    ) -> Tracks {
+        Tracks {
+            entries,
+            next: 0,
+            selector,
+            on_skip,
+            strict,
+            on_decode_error,
+            on_mix_fallback,
+        }
+    }
+
+    fn entry(&self, track: usize) -> &PlaylistEntry {
+        &self.entries[track]
+    }
+
+    // Whether the track after `track` picks up in the same file exactly where `track` ends,
+    // as the tracks from a CUE sheet do. Those play straight through without reopening the file.
+    fn continues(&self, track: usize) -> bool {
+        let current = &self.entries[track];
+        match self.entries.get(track + 1) {
+            Some(next) => self.next == track + 1 && next.path == current.path && Some(next.start) == current.end,
+            None => false,
+        }
+    }
+
+    // Pass on a packet in `track` that wouldn't decode, `position` into the track
+    fn report_decode_error(&mut self, track: usize, position: Duration, e: &PlayerError) {
+        if let Some(on_decode_error) = self.on_decode_error.as_mut() {
+            on_decode_error(&self.entries[track].path, position, e);
+        }
+    }
+
+    // Pass on that the custom mix matrix doesn't fit `track`, which gets the built-in mix instead
+    fn report_mix_fallback(&mut self, track: usize, e: &PlayerError) {
+        if let Some(on_mix_fallback) = self.on_mix_fallback.as_mut() {
+            on_mix_fallback(&self.entries[track].path, e);
+        }
+    }
+
+    // Move on to the next track without opening it, for when `continues` says so
+    fn advance(&mut self) -> usize {
+        self.next += 1;
+        self.next - 1
+    }
+
+    fn skip_to(&mut self, track: usize) {
+        self.next = track.min(self.entries.len());
+    }
+
+    // Open the next track that will open, reporting the ones that won't through `on_skip` and moving on.
+    // For the first track, if nothing opens, the last error is returned for Player::open to report instead.
+    fn open_next(&mut self, first: bool) -> Result<Option<(usize, Source)>, PlayerError> {
+        while self.next < self.entries.len() {
+            let index = self.next;
+            self.next += 1;
+
+            match Source::open_entry(&self.entries[index], &self.selector) {
+                Ok(source) => return Ok(Some((index, source))),
+                Err(e) if first && self.next == self.entries.len() => return Err(e),
+                Err(e) => {
+                    if let Some(on_skip) = self.on_skip.as_mut() {
+                        on_skip(&self.entries[index].path, &e);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+// The rest of the decoder thread's work, once the output format is known
+pub(crate) type DecodeJob = Box<dyn FnOnce(usize, Source, Tracks) -> Result<(), PlayerError> + Send>;
+
+// The decoder thread: open the first file, report back what's in it, then do whatever job the player sends
+pub(crate) fn open_and_decode(
+    mut tracks: Tracks,
+    info: mpsc::SyncSender<Result<StreamInfo, PlayerError>>,
+    job: mpsc::Receiver<DecodeJob>,
+) -> Result<(), PlayerError> {
+    // If nothing opens, the error goes back to Player::open rather than out of this thread
+    let (track, source) = match tracks.open_next(true) {
+        Ok(Some(opened)) => opened,
+        Ok(None) => {
+            let _ = info.send(Err(PlayerError::EmptyPlaylist));
+            return Ok(());
+        }
+        Err(e) => {
+            let _ = info.send(Err(e));
+            return Ok(());
+        }
+    };
+    let _ = info.send(Ok(source.info()));
+
+    // The player hangs up without sending a job if it couldn't set up the output
+    match job.recv() {
+        Ok(job) => job(track, source, tracks),
+        Err(_) => Ok(()),
+    }
+}
+
+// The job for the decoder thread: set up a pipeline into `producer`, report whether that worked
+// through `ready`, then run it
+pub(crate) fn pipeline_job<T: OutputSample>(
+    config: SinkConfig,
+    mix_matrix: Option<MixMatrix>,
+    producer: SampleProducer<T>,
+    state: Arc<State>,
+    ready: mpsc::SyncSender<Result<(), PlayerError>>,
+) -> DecodeJob {
+    Box::new(move |track, source, tracks| {
+        let mut pipeline = match Pipeline::new(track, source, tracks, config, mix_matrix, producer, state) {
+            Ok(pipeline) => {
+                let _ = ready.send(Ok(()));
+                pipeline
+            }
+            Err(e) => {
+                // Whoever's waiting on `ready` reports this one
+                let _ = ready.send(Err(e));
+                return Ok(());
+            }
+        };
+        pipeline.run()
+    })
+}
+
+// Decodes each track, resamples it to the sink's format, and feeds it into the ring buffer
+pub(crate) struct Pipeline<T: OutputSample> {
+    source: Source,
+    // Which of `tracks` is in `source`
+    track: usize,
+    tracks: Tracks,
+    config: SinkConfig,
+    mix_matrix: Option<MixMatrix>,
+    resampler: ResamplingContext,
+    producer: SampleProducer<T>,
+    state: Arc<State>,
+    // After a seek, the exact timestamp playback should start from (in the stream's time base).
+    // It's turned into `skip` when the first frame after the seek comes out of the decoder.
+    trim_to: Option<i64>,
+    // Resampled samples (counting every channel) still to throw away to land on the seek target
+    skip: usize,
+    // For a track that ends part way through its file, how many more samples (counting every
+    // channel) there are before the end
+    remaining: Option<u64>,
+    // Set once the track has reached its end part way through the file. Anything still coming
+    // out of the decoder or resampler after that is thrown away.
+    track_ended: bool,
+    // The end of the last audio packet read (in the stream's time base), for picking up where we
+    // left off after reconnecting to a stream
+    read_to: Option<i64>,
+    // The timestamp of a bad packet that couldn't say how long it was. The silence standing in for it
+    // goes in once the next frame shows where the audio picks up again.
+    gap_from: Option<i64>,
+    // Reused for interleaving planar frames that skip the resampler
+    interleaved: Vec<T::Resampled>,
+    // The last track that was warned about the custom mix matrix not fitting, so it's only warned about once
+    mix_warned: Option<usize>,
+}
+
+impl<T: OutputSample> Pipeline<T> {
+    pub(crate) fn new(
+        track: usize,
+        source: Source,
+        tracks: Tracks,
+        config: SinkConfig,
+        mix_matrix: Option<MixMatrix>,
+        producer: SampleProducer<T>,
+        state: Arc<State>,
+    ) -> Result<Pipeline<T>, PlayerError> {
+        let resampler = create_resampler(&source, &config, mix_matrix.as_ref())?;
+
+        let mut pipeline = Pipeline {
+            source,
+            track,
+            tracks,
+            config,
+            mix_matrix,
+            resampler,
+            producer,
+            state,
+            trim_to: None,
+            skip: 0,
+            remaining: None,
+            track_ended: false,
+            read_to: None,
+            gap_from: None,
+            interleaved: Vec::new(),
+            mix_warned: None,
+        };
+        pipeline.begin_track()?;
+        pipeline.state.clock.reset_anchor(pipeline.anchor(Duration::from_secs(0)));
+        Ok(pipeline)
+    }
+
+    pub(crate) fn run(&mut self) -> Result<(), PlayerError> {
+        let result = self.decode_tracks();
+        if result.is_err() {
+            self.state.stopped.store(true, Ordering::SeqCst);
+        }
+
+        // A consumer on its own thread might be waiting for samples that aren't coming now
+        self.producer.wake_consumer();
+        result
+    }
+
+    fn decode_tracks(&mut self) -> Result<(), PlayerError> {
+        loop {
+            self.decode_packets()?;
+            if self.state.is_stopped() {
+                return Ok(());
+            }
+
+            // Out of packets (or out of track). Let the decoder know so it hands over any frames
+            // it's holding on to... unless it's already been told, say after skipping past the last track
+            // while it played out, or seeking in a pipe that had already finished
+            match self.source.decoder.send_eof() {
+                Ok(()) | Err(ffmpeg::Error::Eof) => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.receive_and_queue_audio_frames()?;
+
+            // Go straight on to the next track while this one is still playing out of the buffer,
+            // so there's no gap between them
+            if let Some((track, source)) = self.open_next_track() {
+                self.start_track(track, source)?;
+                continue;
+            }
+
+            // ...and get the last few samples out of the resampler's delay buffer
+            self.flush_resampler()?;
+
+            // Everything is queued. Wait for the output callback to play it all,
+            // unless we get asked to seek or skip back in the meantime.
+            self.state.finished.store(true, Ordering::SeqCst);
+            self.producer.wake_consumer();
+            let state = &self.state;
+            self.producer.wakeup().wait_until(|| {
+                state.drained.load(Ordering::SeqCst) || state.is_stopped() || state.jump_pending()
+            });
+
+            if !self.state.jump_pending() || self.state.is_stopped() {
+                return Ok(());
+            }
+            self.state.finished.store(false, Ordering::SeqCst);
+            self.state.drained.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // Feed packets through the decoder until the end of the track, or until we're stopped
+    fn decode_packets(&mut self) -> Result<(), PlayerError> {
+        // The main loop!
+        loop {
+            // Hold off while paused (but seeking while paused is fine)
+            let state = &self.state;
+            self.producer.wakeup().wait_until(|| {
+                !state.paused.load(Ordering::SeqCst) || state.is_stopped() || state.jump_pending()
+            });
+
+            if self.state.is_stopped() {
+                return Ok(());
+            }
+
+            if let Some(track) = self.state.take_skip() {
+                self.skip_to_track(track)?;
+            }
+            if let Some(position) = self.state.take_seek() {
+                self.seek(position)?;
+            }
+            if let Some(index) = self.state.take_stream_switch() {
+                self.switch_stream(index)?;
+            }
+            if self.track_ended {
+                return Ok(());
+            }
+
+            let mut packet = Packet::empty();
+            match packet.read(&mut self.source.ictx) {
+                Ok(()) => {}
+                // A live stream doesn't end by itself, so running out means the connection dropped
+                Err(ffmpeg::Error::Eof) if self.source.is_live() => match self.reconnect()? {
+                    true => continue,
+                    false => return Ok(()),
+                },
+                Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) if self.source.network => match self.reconnect()? {
+                    true => continue,
+                    false => return Err(e.into()),
+                },
+                // Same as ictx.packets(): skip packets that fail to read
+                Err(_) => continue,
+            }
+
+            // Look for audio packets (ignore video and others)
+            if packet.stream() == self.source.audio_stream_index {
+                if let Some(pts) = packet.pts() {
+                    self.read_to = Some(pts + packet.duration());
+                }
+                if self.source.network {
+                    self.update_stream_title();
+                }
+
+                // Send the packet to the decoder; it will combine them into frames.
+                // In practice though, 1 packet = 1 frame
+                if let Err(e) = self.source.decoder.send_packet(&packet) {
+                    self.bad_packet(&packet, e.into())?;
+                    continue;
+                }
+
+                // Queue the audio for playback (and block if the queue is full)
+                self.receive_and_queue_audio_frames()?;
+                if self.track_ended {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Jump to `position` in the current track
+    fn seek(&mut self, position: Duration) -> Result<(), PlayerError> {
+        // A pipe can't go back, and there's no telling how far ahead it would have to read
+        if !self.source.seekable {
+            return Ok(());
+        }
+
+        // Near the end of a track the decoder can already be into the next one, but the seek is
+        // meant for the track that's still playing
+        let heard = self.state.clock.now().track;
+        if heard != self.track {
+            self.skip_to_track(heard)?;
+            if self.track_ended {
+                return Ok(());
+            }
+        }
+        self.seek_in_track(position)
+    }
+
+    // Seek within the track being decoded, whatever is playing
+    fn seek_in_track(&mut self, position: Duration) -> Result<(), PlayerError> {
+        if !self.source.seekable {
+            return Ok(());
+        }
+        self.seek_source(position)?;
+
+        // Forget about everything from the old position: in the resampler and the ring buffer
+        self.resampler = create_resampler(&self.source, &self.config, self.mix_matrix.as_ref())?;
+        self.producer.clear();
+
+        // The first sample pushed from here on is the one at `position`
+        self.state.clock.reset_anchor(self.anchor(position));
+        Ok(())
+    }
+
+    // Point the decoder at `position` in the current track
+    fn seek_source(&mut self, position: Duration) -> Result<(), PlayerError> {
+        // Seek to the packet at or before the position; it's trimmed to the exact sample later
+        let start = self.tracks.entry(self.track).start;
+        let timestamp = self.source.start_time() + (start + position).as_micros() as i64;
+        self.source.ictx.seek(timestamp, ..timestamp)?;
+        self.source.decoder.flush();
+
+        self.trim_to = Some(timestamp.rescale(ffmpeg::rescale::TIME_BASE, self.source.time_base));
+        self.skip = 0;
+        self.remaining = self.samples_left(position);
+        self.track_ended = false;
+        self.read_to = None;
+        self.gap_from = None;
+        Ok(())
+    }
+
+    // Get ready to decode the track that's just been put in `source`, from its start
+    fn begin_track(&mut self) -> Result<(), PlayerError> {
+        self.check_mix_matrix(source_input(&self.source));
+        *self.state.stream_title.lock().unwrap() = None;
+        self.state.audio_stream.store(self.source.audio_stream_index, Ordering::SeqCst);
+        if self.tracks.entry(self.track).start > Duration::from_secs(0) {
+            return self.seek_source(Duration::from_secs(0));
+        }
+
+        self.trim_to = None;
+        self.skip = 0;
+        self.remaining = self.samples_left(Duration::from_secs(0));
+        self.track_ended = false;
+        self.read_to = None;
+        self.gap_from = None;
+        Ok(())
+    }
+
+    // Drop whatever is buffered and go straight to `track`
+    fn skip_to_track(&mut self, track: usize) -> Result<(), PlayerError> {
+        self.producer.clear();
+        self.tracks.skip_to(track);
+
+        match self.open_next_track() {
+            Some((track, source)) => {
+                self.resampler = create_resampler(&source, &self.config, self.mix_matrix.as_ref())?;
+                self.source = source;
+                self.track = track;
+                self.begin_track()?;
+                self.state.clock.reset_anchor(self.anchor(Duration::from_secs(0)));
+            }
+            // Skipped past the last track, so there's nothing left to play
+            None => self.track_ended = true,
+        }
+        Ok(())
+    }
+
+    fn open_next_track(&mut self) -> Option<(usize, Source)> {
+        match self.tracks.open_next(false) {
+            Ok(next) => next,
+            // Only happens for the first track
+            Err(_) => None,
+        }
+    }
+
+    fn start_track(&mut self, track: usize, source: Source) -> Result<(), PlayerError> {
+        // If the new track is in the same format as the last one, keep using the same resampler
+        // without flushing it, so the two join up seamlessly. Otherwise finish off the old one first.
+        // That's also needed if the old track was cut off part way through its file (the rest of
+        // it is thrown away), or if the new one starts part way through (so it can be trimmed).
+        if !self.same_format(&source) || self.track_ended || self.tracks.entry(track).start > Duration::from_secs(0) {
+            self.flush_resampler()?;
+            self.resampler = create_resampler(&source, &self.config, self.mix_matrix.as_ref())?;
+        }
+
+        self.source = source;
+        self.track = track;
+        self.begin_track()?;
+        self.state.clock.push_anchor(self.anchor(Duration::from_secs(0)));
+        Ok(())
+    }
+
+    // Change to another audio stream in the same file, carrying on from what's being heard
+    fn switch_stream(&mut self, index: usize) -> Result<(), PlayerError> {
+        // A file without that stream carries on with the one it's playing
+        if index == self.source.audio_stream_index || self.source.select_stream(index).is_err() {
+            return Ok(());
+        }
+        self.state.audio_stream.store(index, Ordering::SeqCst);
+
+        if self.source.seekable {
+            // Until the end of the previous track has played out, the new stream starts at the beginning
+            let playhead = self.state.clock.now();
+            let position = if playhead.track == self.track { playhead.position } else { Duration::from_secs(0) };
+            return self.seek_in_track(position);
+        }
+
+        self.check_mix_matrix(source_input(&self.source));
+
+        // Can't go back, so pick up the new stream wherever it's got to
+        if !self.same_format(&self.source) {
+            self.flush_resampler()?;
+            self.resampler = create_resampler(&self.source, &self.config, self.mix_matrix.as_ref())?;
+        }
+        Ok(())
+    }
+
+    // Warn (once per track) if the custom mix matrix is for a different channel count than `input`.
+    // The resampler uses the built-in mix for it instead of stopping playback part way through a playlist.
+    fn check_mix_matrix(&mut self, input: Definition) {
+        let output = output_layout(self.config.channels);
+        let fits = match self.mix_matrix.as_ref() {
+            Some(matrix) => matrix.fits(input.channel_layout, output),
+            None => true,
+        };
+        if fits || self.mix_warned == Some(self.track) {
+            return;
+        }
+
+        let e = PlayerError::MixMatrix {
+            inputs: input.channel_layout.channels() as usize,
+            outputs: output.channels() as usize,
+        };
+        self.mix_warned = Some(self.track);
+        self.tracks.report_mix_fallback(self.track, &e);
+    }
+
+    // Whether `source` decodes to what the resampler is set up for
+    fn same_format(&self, source: &Source) -> bool {
+        *self.resampler.input() == source_input(source)
+    }
+
+    // The connection to a stream has dropped, and ffmpeg's own reconnecting didn't bring it back.
+    // Open it again and carry on from where we were (or from wherever a live stream has got to).
+    // Returns false if it won't open again.
+    fn reconnect(&mut self) -> Result<bool, PlayerError> {
+        let path = self.tracks.entry(self.track).path.clone();
+        let selector = StreamSelector::Index(self.source.audio_stream_index);
+        let mut delay = RECONNECT_DELAY;
+
+        for _ in 0..RECONNECT_ATTEMPTS {
+            self.sleep(delay);
+            delay *= 2;
+            if self.state.is_stopped() {
+                // The main loop takes care of it from here
+                return Ok(true);
+            }
+
+            let source = match Source::open_with(&path, &selector) {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+
+            // Same as going on to the next track: keep the resampler if we can, so there's no click
+            if !self.same_format(&source) {
+                self.flush_resampler()?;
+                self.resampler = create_resampler(&source, &self.config, self.mix_matrix.as_ref())?;
+            }
+            self.source = source;
+
+            // The packet timestamps carry on from the old connection, so trimming lines the two up exactly
+            if let (true, Some(read_to)) = (self.source.seekable, self.read_to) {
+                let timestamp = read_to.rescale(self.source.time_base, ffmpeg::rescale::TIME_BASE);
+                if self.source.ictx.seek(timestamp, ..timestamp).is_ok() {
+                    self.trim_to = Some(read_to);
+                    self.skip = 0;
+                }
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // Wait for `duration`, unless we're stopped first
+    fn sleep(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !self.state.is_stopped() {
+            let now = Instant::now();
+            if now >= until {
+                return;
+            }
+            thread::sleep((until - now).min(Duration::from_millis(100)));
+        }
+    }
+
+    // The decoder has rejected `packet`. Unless we're being strict, count it, let the caller know,
+    // and put silence in its place so everything after it stays in time.
+    fn bad_packet(&mut self, packet: &Packet, e: PlayerError) -> Result<(), PlayerError> {
+        if self.tracks.strict {
+            return Err(e);
+        }
+        self.state.bad_packets.fetch_add(1, Ordering::SeqCst);
+        let position = packet.pts().map(|pts| self.position_of(pts)).unwrap_or_default();
+        self.tracks.report_decode_error(self.track, position, &e);
+
+        match (packet.pts(), packet.duration()) {
+            (_, duration) if duration > 0 => self.queue_silence(duration),
+            (Some(pts), _) => {
+                self.gap_from.get_or_insert(pts);
+                Ok(())
+            }
+            // No way of telling how much is missing
+            (None, _) => Ok(()),
+        }
+    }
+
+    // Where a timestamp (in the stream's time base) is in the current track
+    fn position_of(&self, timestamp: i64) -> Duration {
+        let micros = timestamp.rescale(self.source.time_base, ffmpeg::rescale::TIME_BASE) - self.source.start_time();
+        let start = self.tracks.entry(self.track).start;
+        Duration::from_micros(micros.max(0) as u64).saturating_sub(start)
+    }
+
+    // Fill `duration` (in the stream's time base) with silence, for a packet that didn't decode
+    fn queue_silence(&mut self, duration: i64) -> Result<(), PlayerError> {
+        let seconds = (duration as f64 * f64::from(self.source.time_base)).min(MAX_CONCEALMENT.as_secs_f64());
+        let frames = (seconds * f64::from(self.config.sample_rate)).round() as usize;
+        if frames == 0 {
+            return Ok(());
+        }
+
+        let micros = (seconds * 1_000_000.0) as u64;
+        self.state.concealed.fetch_add(micros, Ordering::SeqCst);
+        let silence = vec![<T::Resampled as Sample>::from(&0.0); frames * self.config.channels as usize];
+        self.queue(&silence)
+    }
+
+    fn update_stream_title(&self) {
+        if let Some(title) = icy_stream_title(&self.source.ictx) {
+            *self.state.stream_title.lock().unwrap() = Some(title);
+        }
+    }
+
+    // The current track has reached its end part way through the file
+    fn end_track(&mut self) {
+        if self.tracks.continues(self.track) {
+            // The next track is the rest of this file, so keep decoding and just mark where it starts
+            self.track = self.tracks.advance();
+            self.remaining = self.samples_left(Duration::from_secs(0));
+            self.state.clock.push_anchor(self.anchor(Duration::from_secs(0)));
+        } else {
+            self.track_ended = true;
+        }
+    }
+
+    // How long the current track is: from the playlist if it's part of a file, otherwise from the container
+    fn track_duration(&self) -> Option<Duration> {
+        let entry = self.tracks.entry(self.track);
+        match entry.end {
+            Some(end) => Some(end.saturating_sub(entry.start)),
+            None => self.source.duration().map(|duration| duration.saturating_sub(entry.start)),
+        }
+    }
+
+    // Marks the next sample to be pushed as being at `position` in the current track
+    fn anchor(&self, position: Duration) -> Anchor {
+        Anchor {
+            index: self.producer.samples_written(),
+            position,
+            track: self.track,
+            duration: self.track_duration(),
+        }
+    }
+
+    // How many output samples (counting every channel) there are from `position` to the end of the
+    // current track, if it ends part way through its file
+    fn samples_left(&self, position: Duration) -> Option<u64> {
+        let entry = self.tracks.entry(self.track);
+        let left = entry.end?.saturating_sub(entry.start + position);
+        let frames = (left.as_secs_f64() * f64::from(self.config.sample_rate)).round() as u64;
+        Some(frames * u64::from(self.config.channels))
+    }
+
+    fn receive_and_queue_audio_frames(&mut self) -> Result<(), PlayerError> {
+        let mut decoded = frame::Audio::empty();
+
+        // Ask the decoder for frames
+        while self.source.decoder.receive_frame(&mut decoded).is_ok() {
+            // Broadcast streams and concatenated files can change rate or layout part way through.
+            // Play out what the old resampler is holding on to, then start a new one for the new format.
+            let input = frame_input(&decoded);
+            if *self.resampler.input() != input {
+                self.check_mix_matrix(input);
+                self.flush_resampler()?;
+                self.resampler = resampler_for(input, &self.config, self.mix_matrix.as_ref())?;
+            }
+
+            if let Some(target) = self.trim_to.take() {
+                self.skip = self.samples_before(target, &decoded);
+            }
+
+            // Now we know where the audio picks up after a bad packet, fill in what's missing
+            if let (Some(from), Some(to)) = (self.gap_from.take(), decoded.timestamp()) {
+                if to > from {
+                    self.queue_silence(to - from)?;
+                }
+            }
+
+            // Audio that's already just what the sink wants goes straight into the ring buffer.
+            // Planar frames only need interleaving on the way.
+            if self.bypasses_resampler(&decoded) {
+                if decoded.is_packed() {
+                    self.queue(packed::<T::Resampled>(&decoded)?)?;
+                } else {
+                    let mut interleaved = std::mem::take(&mut self.interleaved);
+                    interleave(&planar::<T::Resampled>(&decoded)?, &mut interleaved);
+                    let queued = self.queue(&interleaved);
+                    self.interleaved = interleaved;
+                    queued?;
+                }
+                continue;
+            }
+
+            // Resample the frame's audio into another frame
+            let mut resampled = frame::Audio::empty();
+            self.resampler.run(&decoded, &mut resampled)?;
+
+            self.queue_samples(&resampled)?;
+        }
+        Ok(())
+    }
+
+    // Whether `frame` is already at the sink's rate, layout and sample type (packed or planar). The
+    // resampler would at most interleave it, and there's nothing in its delay buffer for frames like that.
+    // A custom mix matrix only gets in the way if it's for these channels; otherwise the resampler ignores it.
+    fn bypasses_resampler(&self, frame: &frame::Audio) -> bool {
+        let input = frame_input(frame);
+        let output = self.resampler.output();
+        let mixed = self.mix_matrix.as_ref().is_some_and(|matrix| matrix.fits(input.channel_layout, output.channel_layout));
+        !mixed
+            && input.format.packed() == output.format
+            && input.rate == output.rate
+            && input.channel_layout == output.channel_layout
+    }
+
+    // How many output samples there are between the start of `frame` and `target`
+    fn samples_before(&self, target: i64, frame: &frame::Audio) -> usize {
+        let start = match frame.timestamp() {
+            Some(start) if start < target => start,
+            _ => return 0,
+        };
+
+        let seconds = (target - start) as f64 * f64::from(self.source.time_base);
+        let frames = (seconds * f64::from(self.config.sample_rate)).round() as usize;
+        frames * self.config.channels as usize
+    }
+
+    // Drain whatever the resampler is still holding on to at the end of the stream
+    fn flush_resampler(&mut self) -> Result<(), PlayerError> {
+        loop {
+            // Unlike run(), flush() needs an output frame that's already allocated in the output format
+            let output = self.resampler.output();
+            let mut resampled = frame::Audio::new(output.format, 4096, output.channel_layout);
+            resampled.set_rate(output.rate);
+
+            self.resampler.flush(&mut resampled)?;
+            if resampled.samples() == 0 {
+                return Ok(());
+            }
+
+            self.queue_samples(&resampled)?;
+        }
+    }
+
+    // Push a resampled frame into the ring buffer, blocking until there's room for all of it
+    fn queue_samples(&mut self, resampled: &frame::Audio) -> Result<(), PlayerError> {
+        // DON'T just use resampled.data(0).len() -- it might not be fully populated
+        // Grab the right number of bytes based on sample count, bytes per sample, and number of channels.
+        self.queue(packed::<T::Resampled>(resampled)?)
+    }
+
+    // Push interleaved samples in the output format into the ring buffer
+    fn queue(&mut self, both_channels: &[T::Resampled]) -> Result<(), PlayerError> {
+
+        // Right after a seek, drop whatever comes before the exact position we were asked for
+        let skipped = self.skip.min(both_channels.len());
+        self.skip -= skipped;
+        let mut samples = &both_channels[skipped..];
+
+        // If the track ends part way through the frame, only the first part is queued,
+        // and the rest either goes to the next track (if it carries on in this file) or nowhere
+        while !samples.is_empty() && !self.track_ended {
+            let count = match self.remaining {
+                Some(remaining) => samples.len().min(remaining as usize),
+                None => samples.len(),
+            };
+            if !self.push_samples(&samples[..count]) {
+                return Ok(());
+            }
+            samples = &samples[count..];
+
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining -= count as u64;
+                if *remaining == 0 {
+                    self.end_track();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Buffer samples for playback, converting them to the output type on the way in.
+    // Returns false if they're no longer wanted.
+    fn push_samples(&mut self, samples: &[T::Resampled]) -> bool {
+        // Normally they all go in at once, but more than the buffer holds have to go in pieces
+        let state = &self.state;
+        let mut samples = samples.iter().map(T::from_resampled).peekable();
+        while samples.peek().is_some() {
+            // Wait until the output callback has made enough room. If we're stopped, or the
+            // player wants to seek somewhere else, these samples aren't needed any more.
+            if !self.producer.wait_for_space(samples.len(), || state.is_stopped() || state.jump_pending()) {
+                return false;
+            }
+            self.producer.push_iter(&mut samples);
+        }
+        true
+    }
+}
+
+// What the decoder says it produces
+fn source_input(source: &Source) -> Definition {
+    Definition {
+        format: source.decoder.format(),
+        channel_layout: source.channel_layout(),
+        rate: source.decoder.rate(),
+    }
+}
+
+// What's actually in a decoded frame, which can differ from what the decoder said up front
+fn frame_input(frame: &frame::Audio) -> Definition {
+    let layout = frame.channel_layout();
+    Definition {
+        format: frame.format(),
+        // Same as Source::channel_layout: guess from the channel count if the frame doesn't say
+        channel_layout: if layout.is_empty() { ChannelLayout::default(i32::from(frame.channels())) } else { layout },
+        rate: frame.rate(),
+    }
+}
+
+// Set up a resampler from the decoder's format to the sink's, mixing channels if their counts differ
+fn create_resampler(
+    source: &Source,
+    config: &SinkConfig,
+    mix_matrix: Option<&MixMatrix>,
+) -> Result<ResamplingContext, PlayerError> {
+    resampler_for(source_input(source), config, mix_matrix)
+}
+
+fn resampler_for(
+    input: Definition,
+    config: &SinkConfig,
+    mix_matrix: Option<&MixMatrix>,
+) -> Result<ResamplingContext, PlayerError> {
+    let input_layout = input.channel_layout;
+    let output_layout = output_layout(config.channels);
+
+    let mut resampler = ResamplingContext::get(
+        input.format,
+        input_layout,
+        input.rate,
+
+        config.sample_format.as_ffmpeg_sample(),
+        output_layout,
+        config.sample_rate
+    )?;
+
+    // A custom matrix for another channel count gives way to the built-in one (check_mix_matrix warns about it)
+    let custom = mix_matrix.filter(|matrix| matrix.fits(input_layout, output_layout));
+    let builtin = MixMatrix::for_layouts(input_layout, output_layout);
+    if let Some(matrix) = custom.or(builtin.as_ref()) {
+        set_matrix(&mut resampler, matrix)?;
+    }
+
+    Ok(resampler)
+}
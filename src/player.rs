@@ -0,0 +1,569 @@
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use cpal::{Sample, SampleFormat};
+
+use crate::avio::Reader;
+use crate::buffer::{sample_buffer, Wakeup};
+use crate::clock::Clock;
+use crate::control::{Command, Controller, DecodeErrors, Message, PlaybackState, Status};
+use crate::decode::StreamSelector;
+use crate::error::PlayerError;
+use crate::mix::MixMatrix;
+use crate::output::{write_audio, CpalSink, OutputSample, SampleRatePolicy};
+use crate::pipeline::{open_and_decode, pipeline_job, DecodeJob, State, Tracks};
+use crate::playlist::PlaylistEntry;
+use crate::sink::{AudioSink, ErrorCallback, SampleCallback, SinkConfig};
+use crate::volume::{GainRamp, Volume};
+
+/// Plays audio files on an output device.
+///
+/// f32, i16 and u16 devices are all supported. A list of files plays back to back without
+/// gaps: the next file is opened while the end of the current one is still in the buffer,
+/// and everything goes through the same output stream.
+///
+/// Decoding happens on a background thread which keeps a ring buffer topped up,
+/// and the sink's output callback drains that buffer. By default the sink is a cpal device,
+/// but anything implementing `AudioSink` will do (see `open_with_sink`).
+///
+/// The player can be controlled directly, or from other threads through a `Controller`.
+pub struct Player {
+    sink: Box<dyn AudioSink>,
+    decode_thread: Option<JoinHandle<Result<(), PlayerError>>>,
+    state: Arc<State>,
+    wakeup: Arc<Wakeup>,
+    // Interrupts the callback of a sink that waits for samples
+    consumer_wakeup: Arc<Wakeup>,
+    volume: Arc<Volume>,
+    messages: mpsc::Receiver<Message>,
+    sender: mpsc::Sender<Message>,
+    // The first error from the sink or a command, returned from wait()
+    error: Option<PlayerError>,
+}
+
+/// Settings for `Player::open_with`.
+pub struct PlayerOptions {
+    /// The device to play on. Defaults to the default host's default output device.
+    pub device: Option<cpal::Device>,
+    /// How to pick the device's sample rate.
+    pub sample_rate: SampleRatePolicy,
+    /// A custom mix from the file's channels to the device's. When not set, the built-in
+    /// matrices from `MixMatrix::for_layouts` are used, then swresample's defaults.
+    pub mix_matrix: Option<MixMatrix>,
+    /// How much decoded audio to keep buffered ahead of the device, in milliseconds.
+    pub buffer_ms: u32,
+    /// How much audio to buffer before starting to play, and again whenever the buffer runs dry
+    /// part way through, in milliseconds. Silence plays meanwhile. 0 (the default) starts straight
+    /// away, which suits local files; streams over a network want a second or two. The buffer is
+    /// made this much bigger than `buffer_ms` to fit it.
+    pub prebuffer_ms: u32,
+    /// Which audio stream to play from files that have more than one.
+    pub audio_stream: StreamSelector,
+    /// Called (on the decoder thread) for each file in a playlist that can't be opened.
+    /// Those files are skipped either way.
+    pub on_skip: Option<SkipHandler>,
+    /// Stop with an error at the first packet that won't decode. Otherwise (the default) bad packets
+    /// are skipped, with silence in their place, and counted in `Player::decode_errors`.
+    pub strict: bool,
+    /// Called (on the decoder thread) for each packet that won't decode, when not `strict`.
+    pub on_decode_error: Option<DecodeErrorHandler>,
+    /// Called (on the decoder thread) for each file with a different number of channels than
+    /// `mix_matrix` is for. Those files play with the built-in mix instead.
+    pub on_mix_fallback: Option<SkipHandler>,
+}
+
+/// Gets told about each playlist entry that's skipped, and why.
+pub type SkipHandler = Box<dyn FnMut(&Path, &PlayerError) + Send>;
+
+/// Gets told about each packet that's skipped: which file it's in, how far into the track, and the error.
+pub type DecodeErrorHandler = Box<dyn FnMut(&Path, Duration, &PlayerError) + Send>;
+
+impl Default for PlayerOptions {
+    fn default() -> Self {
+        PlayerOptions {
+            device: None,
+            sample_rate: SampleRatePolicy::default(),
+            mix_matrix: None,
+            buffer_ms: 200,
+            prebuffer_ms: 0,
+            audio_stream: StreamSelector::Best,
+            on_skip: None,
+            strict: false,
+            on_decode_error: None,
+            on_mix_fallback: None,
+        }
+    }
+}
+
+impl Player {
+    /// Open `path` on the default output device and get ready to play it. Playback doesn't
+    /// start until `play()` is called, but the decoder starts filling the buffer right away.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Player, PlayerError> {
+        Player::open_with(path, PlayerOptions::default())
+    }
+
+    /// Like `open`, but with control over the output.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: PlayerOptions) -> Result<Player, PlayerError> {
+        Player::open_playlist(Some(path), options)
+    }
+
+    /// Like `open_with`, but reading the file from memory, a pipe or anything else through `reader`.
+    pub fn open_reader(reader: Reader, options: PlayerOptions) -> Result<Player, PlayerError> {
+        Player::open_entries(Some(PlaylistEntry::from_reader("-", reader)), options)
+    }
+
+    /// Open a list of files to play one after the other, gaplessly.
+    ///
+    /// The output is set up for the first file that opens; later files are resampled to match.
+    /// Files that can't be opened are skipped (see `PlayerOptions::on_skip`).
+    pub fn open_playlist<I, P>(paths: I, options: PlayerOptions) -> Result<Player, PlayerError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let entries = paths.into_iter().map(|path| PlaylistEntry::new(path.as_ref()));
+        Player::open_entries(entries, options)
+    }
+
+    /// Like `open_playlist`, but entries can be parts of files (like the tracks of a CUE sheet).
+    ///
+    /// Consecutive entries that split up one file play straight through it without reopening it.
+    pub fn open_entries<I>(entries: I, mut options: PlayerOptions) -> Result<Player, PlayerError>
+    where
+        I: IntoIterator<Item = PlaylistEntry>,
+    {
+        let sink = CpalSink::new(options.device.take(), options.sample_rate);
+        Player::open_with_sink(entries, Box::new(sink), options)
+    }
+
+    /// Like `open_entries`, but playing through any `AudioSink` rather than a cpal device.
+    ///
+    /// `PlayerOptions::device` and `sample_rate` are for cpal, so they're ignored here.
+    pub fn open_with_sink<I>(entries: I, mut sink: Box<dyn AudioSink>, mut options: PlayerOptions) -> Result<Player, PlayerError>
+    where
+        I: IntoIterator<Item = PlaylistEntry>,
+    {
+        ffmpeg::init()?;
+
+        let selector = std::mem::take(&mut options.audio_stream);
+        let tracks = Tracks::new(
+            entries.into_iter().collect(),
+            selector,
+            options.on_skip.take(),
+            options.strict,
+            options.on_decode_error.take(),
+            options.on_mix_fallback.take(),
+        );
+
+        // The decoder thread owns everything ffmpeg-related. It opens the file and tells us
+        // about the audio stream, then waits to hear what format the sink wants.
+        let (info_tx, info_rx) = mpsc::sync_channel(1);
+        let (job_tx, job_rx) = mpsc::sync_channel(1);
+        let (sender, messages) = mpsc::channel();
+        let decode_thread = {
+            let done = DoneGuard(sender.clone());
+            thread::spawn(move || {
+                let _done = done;
+                open_and_decode(tracks, info_tx, job_rx)
+            })
+        };
+        let channel = (sender, messages);
+        let info = info_rx.recv().map_err(|_| PlayerError::DecoderThread)??;
+
+        // Let the sink pick its format, knowing what's in the file
+        let config = sink.configure(&info)?;
+
+        // The ring buffer, resampler and output callback all work in the sink's sample type
+        match config.sample_format {
+            SampleFormat::F32 => Player::start::<f32>(sink, config, options, decode_thread, job_tx, channel),
+            SampleFormat::I16 => Player::start::<i16>(sink, config, options, decode_thread, job_tx, channel),
+            SampleFormat::U16 => Player::start::<u16>(sink, config, options, decode_thread, job_tx, channel),
+        }
+    }
+
+    fn start<T: OutputSample>(
+        mut sink: Box<dyn AudioSink>,
+        config: SinkConfig,
+        options: PlayerOptions,
+        decode_thread: JoinHandle<Result<(), PlayerError>>,
+        job_tx: mpsc::SyncSender<DecodeJob>,
+        (sender, messages): (mpsc::Sender<Message>, mpsc::Receiver<Message>),
+    ) -> Result<Player, PlayerError> {
+        // A buffer to hold audio samples
+        let samples_per_second = config.sample_rate as usize * config.channels as usize;
+        let buffer_ms = options.buffer_ms as usize + options.prebuffer_ms as usize;
+        let capacity = (samples_per_second * buffer_ms / 1000).max(config.channels as usize);
+        let prebuffer = samples_per_second * options.prebuffer_ms as usize / 1000;
+        let (producer, mut consumer) = sample_buffer::<T>(capacity);
+        let wakeup = producer.wakeup();
+        let consumer_wakeup = producer.consumer_wakeup();
+
+        let clock = Clock::new(config.sample_rate, config.channels);
+        let state = Arc::new(State::new(clock));
+        state.buffering.store(prebuffer > 0, Ordering::SeqCst);
+
+        // Hand the decoder thread everything it needs, and wait for it to set up the resampler
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        let job = pipeline_job(config, options.mix_matrix, producer, state.clone(), ready_tx);
+        job_tx.send(job).map_err(|_| PlayerError::DecoderThread)?;
+        ready_rx.recv().map_err(|_| PlayerError::DecoderThread)??;
+
+        // Set up the output callback
+        let volume = Arc::new(Volume::default());
+        let callback_state = state.clone();
+        let callback_volume = volume.clone();
+        let channels = config.channels as usize;
+        let mut gain = GainRamp::new(config.sample_rate);
+        let realtime = sink.is_realtime();
+        let callback: SampleCallback<T> = Box::new(move |data: &mut [T], latency| {
+            // A sink that isn't realtime would rather wait for a full buffer than get silence.
+            // Once everything has been played there's nothing to wait for until a seek.
+            if !realtime {
+                let state = &callback_state;
+                consumer.wait_for_samples(data.len(), || {
+                    state.stopped.load(Ordering::SeqCst)
+                        || state.paused.load(Ordering::SeqCst)
+                        || (state.finished.load(Ordering::SeqCst) && !state.drained.load(Ordering::SeqCst))
+                });
+            }
+
+            // Play silence until there's enough buffered to ride out a slow connection
+            if realtime && callback_state.buffering.load(Ordering::SeqCst) {
+                consumer.discard_stale();
+                if consumer.len() < prebuffer && !callback_state.finished.load(Ordering::SeqCst) {
+                    data.iter_mut().for_each(|sample| *sample = Sample::from(&0.0));
+                    callback_state.clock.record_output(consumer.samples_read(), latency);
+                    return 0;
+                }
+                callback_state.buffering.store(false, Ordering::SeqCst);
+            }
+
+            // Copy to the audio buffer (if there aren't enough samples, write_audio will write silence)
+            let written = write_audio(data, &mut consumer);
+            gain.apply(data, channels, &callback_volume);
+            callback_state.clock.record_output(consumer.samples_read(), latency);
+
+            // Running dry part way through means the decoder can't keep up, so build the buffer back up
+            if realtime && prebuffer > 0 && written < data.len() && !callback_state.finished.load(Ordering::SeqCst) {
+                callback_state.buffering.store(true, Ordering::SeqCst);
+            }
+
+            // Running short after the decoder has finished means the last sample has gone to the sink
+            if written < data.len() && callback_state.finished.load(Ordering::SeqCst) {
+                callback_state.drained.store(true, Ordering::SeqCst);
+                consumer.notify();
+            }
+            written
+        });
+
+        // The player thread stops playback and reports errors from the sink
+        let error_sender = sender.clone();
+        let on_error: ErrorCallback = Box::new(move |e| {
+            let _ = error_sender.send(Message::SinkError(e));
+        });
+        if let Err(e) = sink.start(T::sink_callback(callback), on_error) {
+            // Don't leave the decoder thread waiting for room that's never coming
+            state.stopped.store(true, Ordering::SeqCst);
+            wakeup.wake();
+            return Err(e);
+        }
+
+        Ok(Player {
+            sink,
+            decode_thread: Some(decode_thread),
+            state,
+            wakeup,
+            consumer_wakeup,
+            volume,
+            messages,
+            sender,
+            error: None,
+        })
+    }
+
+    /// Start (or restart) sending audio to the sink.
+    pub fn play(&self) -> Result<(), PlayerError> {
+        self.state.started.store(true, Ordering::SeqCst);
+        self.resume()
+    }
+
+    /// Pause the output, and the decoder along with it.
+    pub fn pause(&self) -> Result<(), PlayerError> {
+        self.state.paused.store(true, Ordering::SeqCst);
+        self.consumer_wakeup.wake();
+        self.sink.pause()
+    }
+
+    /// Pick up where `pause` left off.
+    pub fn resume(&self) -> Result<(), PlayerError> {
+        self.state.paused.store(false, Ordering::SeqCst);
+        self.wakeup.wake();
+        self.sink.play()
+    }
+
+    /// Jump to `position` from the start of the track being heard.
+    ///
+    /// The decoder lands on the packet before `position` and trims the audio to the exact sample.
+    pub fn seek(&self, position: Duration) {
+        self.state.drained.store(false, Ordering::SeqCst);
+        self.state.request_seek(position);
+        self.wakeup.wake();
+    }
+
+    /// Drop whatever's playing and go to the start of `track` (an index into the playlist).
+    ///
+    /// Skipping past the last track finishes playback.
+    pub fn skip_to(&self, track: usize) {
+        self.state.drained.store(false, Ordering::SeqCst);
+        self.state.request_skip(track);
+        self.wakeup.wake();
+    }
+
+    /// Switch to another of the file's audio streams (by its index in the container), carrying on
+    /// from the same position. Ignored if the file has no audio stream with that index.
+    pub fn select_audio_stream(&self, index: usize) {
+        self.state.drained.store(false, Ordering::SeqCst);
+        self.state.request_stream_switch(index);
+        self.wakeup.wake();
+    }
+
+    /// The index of the audio stream being decoded, in its file.
+    pub fn audio_stream(&self) -> usize {
+        self.state.audio_stream.load(Ordering::SeqCst)
+    }
+
+    pub fn next_track(&self) {
+        self.skip_to(self.track() + 1);
+    }
+
+    /// Go back to the start of the current track, or to the previous track if we're only
+    /// a few seconds into this one.
+    pub fn previous_track(&self) {
+        let playhead = self.state.clock.now();
+        if playhead.position > Duration::from_secs(3) || playhead.track == 0 {
+            self.skip_to(playhead.track);
+        } else {
+            self.skip_to(playhead.track - 1);
+        }
+    }
+
+    /// Set the volume as a linear gain, where 1.0 leaves the audio as it is.
+    pub fn set_volume(&self, gain: f32) {
+        self.volume.set_gain(gain);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume.gain()
+    }
+
+    /// Set the volume in decibels, where 0.0 leaves the audio as it is.
+    pub fn set_volume_db(&self, db: f32) {
+        self.volume.set_gain_db(db);
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        self.volume.gain_db()
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.volume.set_muted(muted);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.volume.is_muted()
+    }
+
+    /// How far into the current track the audio coming out of the speakers is.
+    ///
+    /// This takes into account what's still sitting in the ring buffer, and the sink's
+    /// latency (as reported by cpal, for a device).
+    pub fn position(&self) -> Duration {
+        self.state.clock.now().position
+    }
+
+    /// How long the current track is, if the container says.
+    pub fn duration(&self) -> Option<Duration> {
+        self.state.clock.now().duration
+    }
+
+    /// Which track (as an index into the list passed to `open_playlist`) is playing.
+    pub fn track(&self) -> usize {
+        self.state.clock.now().track
+    }
+
+    /// How far behind the output callback the speakers are, as last reported by the sink.
+    pub fn latency(&self) -> Duration {
+        self.sink.latency()
+    }
+
+    /// Whether playback is held up waiting for the buffer to fill (see `PlayerOptions::prebuffer_ms`).
+    pub fn is_buffering(&self) -> bool {
+        self.state.buffering.load(Ordering::SeqCst)
+    }
+
+    /// The title of what's on, from an internet radio stream's ICY metadata.
+    ///
+    /// This changes when the decoder reads the new title, which is a buffer's length ahead of
+    /// what's being heard.
+    pub fn stream_title(&self) -> Option<String> {
+        self.state.stream_title.lock().unwrap().clone()
+    }
+
+    /// How many packets have been skipped because they wouldn't decode, and how much silence went in their place.
+    pub fn decode_errors(&self) -> DecodeErrors {
+        DecodeErrors {
+            bad_packets: self.state.bad_packets.load(Ordering::SeqCst),
+            concealed: Duration::from_micros(self.state.concealed.load(Ordering::SeqCst)),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst) || !self.state.started.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> Status {
+        // wait() sets stopped once everything has played, so drained has to be checked first
+        let state = if self.state.drained.load(Ordering::SeqCst) {
+            PlaybackState::Finished
+        } else if self.state.stopped.load(Ordering::SeqCst) {
+            PlaybackState::Stopped
+        } else if self.is_paused() {
+            PlaybackState::Paused
+        } else if self.is_buffering() {
+            PlaybackState::Buffering
+        } else {
+            PlaybackState::Playing
+        };
+
+        let playhead = self.state.clock.now();
+        Status {
+            state,
+            track: playhead.track,
+            position: playhead.position,
+            duration: playhead.duration,
+            volume: self.volume(),
+            muted: self.is_muted(),
+            stream_title: self.stream_title(),
+            audio_stream: self.audio_stream(),
+        }
+    }
+
+    /// A handle for controlling this player from other threads.
+    pub fn controller(&self) -> Controller {
+        Controller::new(self.sender.clone())
+    }
+
+    /// Stop playback and shut down the decoder thread.
+    pub fn stop(&mut self) -> Result<(), PlayerError> {
+        self.request_stop();
+        self.wait()
+    }
+
+    fn request_stop(&self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        self.wakeup.wake();
+        self.consumer_wakeup.wake();
+        let _ = self.sink.pause();
+    }
+
+    /// Handle any commands sent through a `Controller` without blocking.
+    /// Returns true once the decoder thread is done.
+    pub fn handle_commands(&mut self) -> bool {
+        loop {
+            match self.messages.try_recv() {
+                Ok(Message::DecoderDone) => return true,
+                Ok(message) => self.handle_message(message),
+                Err(mpsc::TryRecvError::Empty) => return false,
+                // Can't happen while we're holding a sender ourselves
+                Err(mpsc::TryRecvError::Disconnected) => return true,
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        let result = match message {
+            Message::Command(command) => self.handle(command),
+            Message::SinkError(e) => Err(e),
+            Message::DecoderDone => Ok(()),
+        };
+
+        // The output isn't going to recover from this, so stop and let wait() report it
+        if let Err(e) = result {
+            self.request_stop();
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn handle(&mut self, command: Command) -> Result<(), PlayerError> {
+        match command {
+            Command::Pause => self.pause()?,
+            Command::Resume => self.resume()?,
+            Command::TogglePause if self.is_paused() => self.resume()?,
+            Command::TogglePause => self.pause()?,
+            Command::Stop => self.request_stop(),
+            Command::Seek(position) => self.seek(position),
+            Command::SkipTo(track) => self.skip_to(track),
+            Command::SelectAudioStream(index) => self.select_audio_stream(index),
+            Command::NextTrack => self.next_track(),
+            Command::PreviousTrack => self.previous_track(),
+            Command::SetVolume(gain) => self.set_volume(gain),
+            Command::AdjustVolumeDb(db) => self.set_volume_db(self.volume_db() + db),
+            Command::SetMuted(muted) => self.set_muted(muted),
+            Command::ToggleMute => self.set_muted(!self.is_muted()),
+            Command::Status(reply) => {
+                let _ = reply.send(self.status());
+            }
+        }
+        Ok(())
+    }
+
+    /// Block until everything has been played (or playback was stopped), then stop the sink.
+    ///
+    /// Commands from `Controller`s are handled while waiting.
+    pub fn wait(&mut self) -> Result<(), PlayerError> {
+        let handle = match self.decode_thread.take() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+
+        while let Ok(message) = self.messages.recv() {
+            match message {
+                Message::DecoderDone => break,
+                message => self.handle_message(message),
+            }
+        }
+
+        // The decoder thread only returns once the buffer has drained, so there's nothing left to
+        // play. A sink waiting on more samples needs telling that none are coming before it stops.
+        self.state.stopped.store(true, Ordering::SeqCst);
+        self.consumer_wakeup.wake();
+        let stopped = self.sink.stop();
+        let result = handle.join().map_err(|_| PlayerError::DecoderThread)?;
+
+        // An error from the output is what actually stopped playback, so it comes first
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => stopped.and(result),
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        // Don't leave the decoder thread blocked on a full buffer
+        self.request_stop();
+        let _ = self.wait();
+    }
+}
+
+// Lets the player know the decoder thread has exited, even if it panicked
+struct DoneGuard(mpsc::Sender<Message>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(Message::DecoderDone);
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::avio::{Reader, SharedReader};
+use crate::cue::CueSheet;
+
+/// One thing to play, from the command line, a directory or a playlist file.
+///
+/// Usually that's a whole file, but a track from a CUE sheet is only part of one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    /// From `#EXTINF` in M3U, `TitleN` in PLS or `TITLE` in a CUE sheet
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// What the playlist says the duration is, if anything
+    pub duration: Option<Duration>,
+    /// Where in the file the entry starts
+    pub start: Duration,
+    /// Where in the file the entry ends, or None to play to the end of the file
+    pub end: Option<Duration>,
+    // Where to read the file from instead of `path`, which is then just a name for it
+    pub(crate) reader: Option<SharedReader>,
+}
+
+impl PlaylistEntry {
+    pub fn new<P: Into<PathBuf>>(path: P) -> PlaylistEntry {
+        PlaylistEntry {
+            path: path.into(),
+            title: None,
+            performer: None,
+            duration: None,
+            start: Duration::from_secs(0),
+            end: None,
+            reader: None,
+        }
+    }
+
+    /// An entry that's read through `reader` instead of from a file. `name` stands in for the
+    /// path in messages.
+    ///
+    /// Clones of the entry share the reader, and it can only be played once between them.
+    pub fn from_reader<P: Into<PathBuf>>(name: P, reader: Reader) -> PlaylistEntry {
+        PlaylistEntry {
+            reader: Some(SharedReader::new(reader)),
+            ..PlaylistEntry::new(name)
+        }
+    }
+
+    /// The title (and performer) if the playlist gave one, otherwise the file name.
+    pub fn name(&self) -> String {
+        match (&self.title, &self.performer, self.path.file_name()) {
+            (Some(title), Some(performer), _) => format!("{} - {}", performer, title),
+            (Some(title), None, _) => title.clone(),
+            (None, _, Some(file_name)) => file_name.to_string_lossy().into_owned(),
+            (None, _, None) => self.path.display().to_string(),
+        }
+    }
+}
+
+impl AsRef<Path> for PlaylistEntry {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Turn a mix of files, directories and playlists into a list of things to play.
+///
+/// Directories are replaced by the files directly inside them, sorted by name, skipping hidden
+/// files, subdirectories and playlists. `.m3u`, `.m3u8`, `.pls` and `.cue` files are replaced by
+/// their entries. `-` reads from standard input. Anything else is passed through as it is. Entries
+/// that turn out to be missing or unplayable are left for the player to skip.
+///
+/// A directory or playlist that can't be read is passed to `on_error` and left out, unless that
+/// leaves nothing at all to play, in which case the first such error is returned.
+pub fn expand_paths<I, P>(paths: I, mut on_error: impl FnMut(&Path, &io::Error)) -> io::Result<Vec<PlaylistEntry>>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let mut expanded = Vec::new();
+    let mut failed = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let entries = if path == Path::new("-") {
+            Ok(vec![PlaylistEntry::from_reader(path, Reader::stdin())])
+        } else if path.is_dir() {
+            read_dir_sorted(path).map(|files| files.into_iter().map(PlaylistEntry::new).collect())
+        } else if is_playlist(path) {
+            read_playlist(path)
+        } else {
+            Ok(vec![PlaylistEntry::new(path)])
+        };
+
+        match entries {
+            Ok(entries) => expanded.extend(entries),
+            Err(e) => failed.push((path.to_path_buf(), e)),
+        }
+    }
+
+    // Skipping what failed is only worth it if there's something else to play
+    let mut failed = failed.into_iter();
+    let first = if expanded.is_empty() { failed.next() } else { None };
+    for (path, e) in failed {
+        on_error(&path, &e);
+    }
+    match first {
+        Some((_, e)) => Err(e),
+        None => Ok(expanded),
+    }
+}
+
+fn read_dir_sorted(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        // A playlist next to the files almost always lists those same files
+        if !hidden && entry.file_type()?.is_file() && !is_playlist(&entry.path()) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether `path` has a playlist extension (`.m3u`, `.m3u8`, `.pls` or `.cue`).
+pub fn is_playlist(path: &Path) -> bool {
+    playlist_extension(path).is_some()
+}
+
+fn playlist_extension(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "m3u" | "m3u8" | "pls" | "cue" => Some(extension),
+        _ => None,
+    }
+}
+
+/// Read an M3U, M3U8, PLS or CUE playlist. Relative entries are resolved against the playlist's directory.
+pub fn read_playlist(path: &Path) -> io::Result<Vec<PlaylistEntry>> {
+    if playlist_extension(path).as_deref() == Some("cue") {
+        return Ok(CueSheet::read(path)?.entries());
+    }
+
+    let text = decode_text(fs::read(path)?);
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    match playlist_extension(path).as_deref() {
+        Some("pls") => Ok(parse_pls(&text, base)),
+        _ => Ok(parse_m3u(&text, base)),
+    }
+}
+
+// M3U8 and PLS are UTF-8, but plain M3U and CUE files are often Latin-1,
+// so fall back to that if it isn't valid UTF-8
+pub(crate) fn decode_text(bytes: Vec<u8>) -> String {
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&byte| byte as char).collect(),
+    };
+    match text.strip_prefix('\u{feff}') {
+        Some(text) => text.to_string(),
+        None => text,
+    }
+}
+
+/// Parse the contents of an M3U or M3U8 playlist.
+///
+/// `#EXTINF:<seconds>,<title>` lines give the title and duration of the entry after them;
+/// any other line starting with `#` is a comment.
+pub fn parse_m3u(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut title = None;
+    let mut duration = None;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // The duration can be followed by attributes (`123 tvg-id="..."`) before the comma,
+            // and their quoted values can have commas of their own
+            let (attributes, name) = match title_comma(info) {
+                Some(comma) => (&info[..comma], info[comma + 1..].trim()),
+                None => (info, ""),
+            };
+            duration = attributes.split_whitespace().next().and_then(parse_seconds);
+            title = if name.is_empty() { None } else { Some(name.to_string()) };
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        entries.push(PlaylistEntry {
+            title: title.take(),
+            duration: duration.take(),
+            ..PlaylistEntry::new(resolve(base, line))
+        });
+    }
+    entries
+}
+
+// The comma between an EXTINF's duration and attributes and its title: the first one outside quotes
+fn title_comma(info: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in info.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse the contents of a PLS playlist (`FileN`, `TitleN` and `LengthN` keys).
+pub fn parse_pls(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    // Keyed by N, since nothing says the keys have to come in order
+    let mut entries: BTreeMap<u32, (Option<String>, Option<String>, Option<Duration>)> = BTreeMap::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        let (key, value) = match line.find('=') {
+            Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+            None => continue,
+        };
+
+        // Keys are case-insensitive in practice ("File1", "file1")
+        let key = key.to_ascii_lowercase();
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let number = match key[split..].parse() {
+            Ok(number) => number,
+            Err(_) => continue,
+        };
+
+        let entry = entries.entry(number).or_default();
+        match &key[..split] {
+            "file" => entry.0 = Some(value.to_string()),
+            "title" if !value.is_empty() => entry.1 = Some(value.to_string()),
+            "length" => entry.2 = parse_seconds(value),
+            _ => {}
+        }
+    }
+
+    entries
+        .into_iter()
+        .filter_map(|(_, (file, title, duration))| {
+            Some(PlaylistEntry {
+                title,
+                duration,
+                ..PlaylistEntry::new(resolve(base, &file?))
+            })
+        })
+        .collect()
+}
+
+// Playlists use -1 for "don't know"
+fn parse_seconds(value: &str) -> Option<Duration> {
+    let seconds: f64 = value.parse().ok()?;
+    if seconds >= 0.0 && seconds.is_finite() {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+// Relative paths are relative to the playlist. URLs are left alone, apart from file:// ones.
+fn resolve(base: &Path, entry: &str) -> PathBuf {
+    if let Some(path) = entry.strip_prefix("file://") {
+        return PathBuf::from(path);
+    }
+    if entry.contains("://") {
+        return PathBuf::from(entry);
+    }
+    base.join(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn m3u_extinf_gives_the_next_entry_its_title_and_duration() {
+        let text = "#EXTM3U\n#EXTINF:123,Artist - Title\nsong.mp3\n\n# a comment\nother.flac\n";
+        let entries = parse_m3u(text, Path::new("/music"));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, PathBuf::from("/music/song.mp3"));
+        assert_eq!(entries[0].title.as_deref(), Some("Artist - Title"));
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(123)));
+        // EXTINF only applies to the entry straight after it
+        assert_eq!(entries[1].path, PathBuf::from("/music/other.flac"));
+        assert_eq!(entries[1].title, None);
+        assert_eq!(entries[1].duration, None);
+    }
+
+    #[test]
+    fn m3u_extinf_attributes_come_before_the_comma() {
+        let text = "#EXTINF:-1 tvg-id=\"radio.one\" group-title=\"News, Talk\",Radio One\nhttp://example.com/live\n";
+        let entries = parse_m3u(text, Path::new("/music"));
+
+        assert_eq!(entries.len(), 1);
+        // -1 means the length isn't known
+        assert_eq!(entries[0].duration, None);
+        assert_eq!(entries[0].path, PathBuf::from("http://example.com/live"));
+        // The comma inside the quoted attribute doesn't count
+        assert_eq!(entries[0].title.as_deref(), Some("Radio One"));
+    }
+
+    #[test]
+    fn m3u_extinf_without_a_title() {
+        let entries = parse_m3u("#EXTINF:5.5,\nsong.mp3\n", Path::new(""));
+        assert_eq!(entries[0].title, None);
+        assert_eq!(entries[0].duration, Some(Duration::from_millis(5500)));
+    }
+
+    #[test]
+    fn pls_keys_can_come_in_any_order_and_case() {
+        let text = "[playlist]\nNumberOfEntries=2\nTitle2=Second\nfile2=b.ogg\nLength2=-1\n\
+                    File1=a.ogg\nTitle1=First\nLength1=60\nVersion=2\n";
+        let entries = parse_pls(text, Path::new("/music"));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, PathBuf::from("/music/a.ogg"));
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(60)));
+        assert_eq!(entries[1].path, PathBuf::from("/music/b.ogg"));
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+        assert_eq!(entries[1].duration, None);
+    }
+
+    #[test]
+    fn pls_entries_without_a_file_are_dropped() {
+        let entries = parse_pls("Title1=Nothing\nFile2=b.ogg\n", Path::new(""));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, PathBuf::from("b.ogg"));
+    }
+
+    #[test]
+    fn resolve_leaves_urls_alone_and_strips_file_urls() {
+        let base = Path::new("/music");
+        assert_eq!(resolve(base, "song.mp3"), PathBuf::from("/music/song.mp3"));
+        assert_eq!(resolve(base, "/elsewhere/song.mp3"), PathBuf::from("/elsewhere/song.mp3"));
+        assert_eq!(resolve(base, "file:///elsewhere/song.mp3"), PathBuf::from("/elsewhere/song.mp3"));
+        assert_eq!(resolve(base, "https://example.com/song.mp3"), PathBuf::from("https://example.com/song.mp3"));
+    }
+
+    #[test]
+    fn decode_text_strips_a_bom() {
+        let mut bytes = "\u{feff}#EXTM3U\n".as_bytes().to_vec();
+        assert_eq!(decode_text(bytes.clone()), "#EXTM3U\n");
+        bytes.drain(..3);
+        assert_eq!(decode_text(bytes), "#EXTM3U\n");
+    }
+
+    #[test]
+    fn decode_text_falls_back_to_latin1() {
+        // "Café.mp3" in Latin-1, which isn't valid UTF-8
+        let bytes = b"Caf\xe9.mp3".to_vec();
+        assert_eq!(decode_text(bytes), "Café.mp3");
+        assert_eq!(decode_text("Café.mp3".as_bytes().to_vec()), "Café.mp3");
+    }
+
+    #[test]
+    fn expanding_skips_what_cannot_be_read() {
+        let mut skipped = Vec::new();
+        let entries = expand_paths(&["/nonexistent/missing.m3u", "song.mp3"], |path, _| skipped.push(path.to_path_buf())).unwrap();
+
+        assert_eq!(entries, vec![PlaylistEntry::new("song.mp3")]);
+        assert_eq!(skipped, vec![PathBuf::from("/nonexistent/missing.m3u")]);
+    }
+
+    #[test]
+    fn expanding_fails_when_nothing_is_left() {
+        let mut skipped = Vec::new();
+        let result = expand_paths(&["/nonexistent/first.m3u", "/nonexistent/second.pls"], |path, _| {
+            skipped.push(path.to_path_buf())
+        });
+
+        // The first failure is the error, and the rest are still reported
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(skipped, vec![PathBuf::from("/nonexistent/second.pls")]);
+    }
+
+    #[test]
+    fn expanding_nothing_is_not_an_error() {
+        let paths: [&str; 0] = [];
+        assert_eq!(expand_paths(&paths, |_, _| panic!("nothing to fail")).unwrap(), vec![]);
+    }
+}
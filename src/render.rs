@@ -0,0 +1,86 @@
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use cpal::SampleFormat;
+
+use crate::control::DecodeErrors;
+use crate::decode::StreamSelector;
+use crate::error::PlayerError;
+use crate::mix::MixMatrix;
+use crate::player::{DecodeErrorHandler, Player, PlayerOptions, SkipHandler};
+use crate::playlist::PlaylistEntry;
+use crate::sink::{FileFormat, FileSink};
+
+/// Settings for `render_to_wav`.
+pub struct RenderOptions {
+    /// The output sample rate. Defaults to the first file's.
+    pub sample_rate: Option<u32>,
+    /// The output channel count. Defaults to the first file's.
+    pub channels: Option<u16>,
+    /// F32 for a 32-bit float WAV, I16 for 16-bit PCM. U16 isn't supported.
+    pub sample_format: SampleFormat,
+    /// A custom mix from the files' channels to the output's, as for `PlayerOptions::mix_matrix`.
+    pub mix_matrix: Option<MixMatrix>,
+    /// Which audio stream to render from files that have more than one.
+    pub audio_stream: StreamSelector,
+    /// Called for each entry that can't be opened, as for `PlayerOptions::on_skip`.
+    pub on_skip: Option<SkipHandler>,
+    /// Fail at the first packet that won't decode, as for `PlayerOptions::strict`.
+    pub strict: bool,
+    /// Called for each packet that's skipped, as for `PlayerOptions::on_decode_error`.
+    pub on_decode_error: Option<DecodeErrorHandler>,
+    /// Called for each entry `mix_matrix` doesn't fit, as for `PlayerOptions::on_mix_fallback`.
+    pub on_mix_fallback: Option<SkipHandler>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: None,
+            channels: None,
+            sample_format: SampleFormat::I16,
+            mix_matrix: None,
+            audio_stream: StreamSelector::Best,
+            on_skip: None,
+            strict: false,
+            on_decode_error: None,
+            on_mix_fallback: None,
+        }
+    }
+}
+
+/// Decode `entries` one after the other into a WAV file at `output`, without a sound device.
+///
+/// The audio goes through the same decode, resample and mix path as it does for a `Player`,
+/// only it's written out as fast as it can be decoded. Returns how many sample frames were written,
+/// and how many packets were skipped because they wouldn't decode.
+pub fn render_to_wav<I>(entries: I, output: &Path, options: RenderOptions) -> Result<(u64, DecodeErrors), PlayerError>
+where
+    I: IntoIterator<Item = PlaylistEntry>,
+{
+    let mut sink = FileSink::new(output, FileFormat::Wav).with_sample_format(options.sample_format);
+    if let Some(sample_rate) = options.sample_rate {
+        sink = sink.with_sample_rate(sample_rate);
+    }
+    if let Some(channels) = options.channels {
+        sink = sink.with_channels(channels);
+    }
+    let frames = sink.frame_counter();
+
+    // A second of audio at a time is plenty when nothing has to happen in real time
+    let options = PlayerOptions {
+        mix_matrix: options.mix_matrix,
+        audio_stream: options.audio_stream,
+        on_skip: options.on_skip,
+        strict: options.strict,
+        on_decode_error: options.on_decode_error,
+        on_mix_fallback: options.on_mix_fallback,
+        buffer_ms: 1000,
+        ..PlayerOptions::default()
+    };
+    let mut player = Player::open_with_sink(entries, Box::new(sink), options)?;
+    player.play()?;
+    player.wait()?;
+
+    Ok((frames.load(Ordering::Relaxed), player.decode_errors()))
+}
@@ -0,0 +1,411 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use cpal::{Sample, SampleFormat};
+
+use crate::buffer::Wakeup;
+use crate::decode::StreamInfo;
+use crate::error::PlayerError;
+use crate::wav::{WavSample, WavWriter};
+
+/// The format a sink wants its audio in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SinkConfig {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_format: SampleFormat,
+}
+
+/// Fills a buffer of interleaved samples, and returns how many of them are audio (the rest is silence).
+///
+/// The `Duration` is how long it'll be until the first sample in the buffer is heard.
+pub type SampleCallback<T> = Box<dyn FnMut(&mut [T], Duration) -> usize + Send>;
+
+/// The player's callback, in whichever sample type the sink asked for in its `SinkConfig`.
+pub enum SinkCallback {
+    F32(SampleCallback<f32>),
+    I16(SampleCallback<i16>),
+    U16(SampleCallback<u16>),
+}
+
+/// Reports an error from a sink once it's running, which stops the player.
+pub type ErrorCallback = Box<dyn FnMut(PlayerError) + Send>;
+
+/// Somewhere for a `Player` to send its audio: a sound device, a file, or nowhere at all.
+///
+/// The player calls `configure` once it knows what's in the first file, then hands `start` the
+/// callback to pull audio from. Nothing should be pulled until `play`. Calling `start` before
+/// `configure` is an error (`PlayerError::SinkNotConfigured`), while `play` and `pause` before
+/// `start` do nothing.
+pub trait AudioSink {
+    /// Pick the format to receive audio in, given the format of the first file.
+    fn configure(&mut self, info: &StreamInfo) -> Result<SinkConfig, PlayerError>;
+
+    /// Get ready to pull audio through `callback`, in the format from `configure`.
+    fn start(&mut self, callback: SinkCallback, on_error: ErrorCallback) -> Result<(), PlayerError>;
+
+    fn play(&self) -> Result<(), PlayerError>;
+
+    fn pause(&self) -> Result<(), PlayerError>;
+
+    /// Stop for good, once everything has been played. File sinks finish off the file here.
+    fn stop(&mut self) -> Result<(), PlayerError> {
+        self.pause()
+    }
+
+    /// How far behind the callback the audio being heard is.
+    fn latency(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    /// Whether the sink pulls audio at the pace it's played, like a sound device does.
+    ///
+    /// Sinks that aren't realtime pull as fast as they can instead, and their callback blocks until it
+    /// has a full buffer of audio rather than padding it out with silence.
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+/// Throws the audio away, either at the pace it would play or as fast as it decodes.
+///
+/// Handy for testing, and for measuring how fast the pipeline runs.
+pub struct NullSink {
+    realtime: bool,
+    config: Option<SinkConfig>,
+    thread: Option<PullThread>,
+}
+
+impl NullSink {
+    /// A sink that pulls audio in real time, like a sound device with nothing plugged in.
+    pub fn realtime() -> NullSink {
+        NullSink {
+            realtime: true,
+            config: None,
+            thread: None,
+        }
+    }
+
+    /// A sink that pulls audio as fast as it can be decoded.
+    pub fn fast() -> NullSink {
+        NullSink {
+            realtime: false,
+            ..NullSink::realtime()
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn configure(&mut self, info: &StreamInfo) -> Result<SinkConfig, PlayerError> {
+        let config = SinkConfig {
+            channels: info.channels,
+            sample_rate: info.rate,
+            sample_format: SampleFormat::F32,
+        };
+        self.config = Some(config);
+        Ok(config)
+    }
+
+    fn start(&mut self, callback: SinkCallback, on_error: ErrorCallback) -> Result<(), PlayerError> {
+        let config = self.config.ok_or(PlayerError::SinkNotConfigured)?;
+        self.thread = Some(match callback {
+            SinkCallback::F32(callback) => PullThread::spawn(callback, Discard, config, self.realtime, on_error),
+            SinkCallback::I16(callback) => PullThread::spawn(callback, Discard, config, self.realtime, on_error),
+            SinkCallback::U16(callback) => PullThread::spawn(callback, Discard, config, self.realtime, on_error),
+        });
+        Ok(())
+    }
+
+    fn play(&self) -> Result<(), PlayerError> {
+        if let Some(thread) = self.thread.as_ref() {
+            thread.set_playing(true);
+        }
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<(), PlayerError> {
+        if let Some(thread) = self.thread.as_ref() {
+            thread.set_playing(false);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), PlayerError> {
+        match self.thread.take() {
+            Some(thread) => thread.stop(),
+            None => Ok(()),
+        }
+    }
+
+    fn is_realtime(&self) -> bool {
+        self.realtime
+    }
+}
+
+/// What a `FileSink` writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Wav,
+    /// Interleaved little-endian samples with no header
+    Raw,
+}
+
+/// Writes the audio to a file as fast as it decodes.
+///
+/// The sample rate and channel count follow the first file unless they're set, and the
+/// samples are 16-bit unless `with_sample_format` asks for f32.
+pub struct FileSink {
+    path: PathBuf,
+    format: FileFormat,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    sample_format: SampleFormat,
+    config: Option<SinkConfig>,
+    thread: Option<PullThread>,
+    frames: Arc<AtomicU64>,
+}
+
+impl FileSink {
+    pub fn new<P: Into<PathBuf>>(path: P, format: FileFormat) -> FileSink {
+        FileSink {
+            path: path.into(),
+            format,
+            sample_rate: None,
+            channels: None,
+            sample_format: SampleFormat::I16,
+            config: None,
+            thread: None,
+            frames: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> FileSink {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn with_channels(mut self, channels: u16) -> FileSink {
+        self.channels = Some(channels);
+        self
+    }
+
+    /// F32 or I16. U16 isn't supported.
+    pub fn with_sample_format(mut self, sample_format: SampleFormat) -> FileSink {
+        self.sample_format = sample_format;
+        self
+    }
+
+    // How many sample frames have been written so far. Still works once the sink is boxed up in a player.
+    pub(crate) fn frame_counter(&self) -> Arc<AtomicU64> {
+        self.frames.clone()
+    }
+
+    fn output<T: WavSample>(&self, config: &SinkConfig) -> Result<FileOutput<T>, PlayerError> {
+        let writer = match self.format {
+            FileFormat::Wav => FileWriter::Wav(WavWriter::create(&self.path, config.channels, config.sample_rate)?),
+            FileFormat::Raw => FileWriter::Raw(BufWriter::new(File::create(&self.path)?)),
+        };
+        Ok(FileOutput {
+            writer: Some(writer),
+            channels: config.channels,
+            frames: self.frames.clone(),
+        })
+    }
+}
+
+impl AudioSink for FileSink {
+    fn configure(&mut self, info: &StreamInfo) -> Result<SinkConfig, PlayerError> {
+        if self.sample_format == SampleFormat::U16 {
+            return Err(PlayerError::UnsupportedFormat("u16 file output".to_string()));
+        }
+
+        let config = SinkConfig {
+            channels: self.channels.unwrap_or(info.channels),
+            sample_rate: self.sample_rate.unwrap_or(info.rate),
+            sample_format: self.sample_format,
+        };
+        self.config = Some(config);
+        Ok(config)
+    }
+
+    fn start(&mut self, callback: SinkCallback, on_error: ErrorCallback) -> Result<(), PlayerError> {
+        let config = self.config.ok_or(PlayerError::SinkNotConfigured)?;
+        self.thread = Some(match callback {
+            SinkCallback::F32(callback) => PullThread::spawn(callback, self.output::<f32>(&config)?, config, false, on_error),
+            SinkCallback::I16(callback) => PullThread::spawn(callback, self.output::<i16>(&config)?, config, false, on_error),
+            SinkCallback::U16(_) => return Err(PlayerError::UnsupportedFormat("u16 file output".to_string())),
+        });
+        Ok(())
+    }
+
+    fn play(&self) -> Result<(), PlayerError> {
+        if let Some(thread) = self.thread.as_ref() {
+            thread.set_playing(true);
+        }
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<(), PlayerError> {
+        if let Some(thread) = self.thread.as_ref() {
+            thread.set_playing(false);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), PlayerError> {
+        match self.thread.take() {
+            Some(thread) => thread.stop(),
+            None => Ok(()),
+        }
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+// Where a pull thread puts the audio it pulls
+trait PullOutput<T>: Send + 'static {
+    fn write(&mut self, samples: &[T]) -> Result<(), PlayerError>;
+
+    fn finish(&mut self) -> Result<(), PlayerError> {
+        Ok(())
+    }
+}
+
+struct Discard;
+
+impl<T> PullOutput<T> for Discard {
+    fn write(&mut self, _: &[T]) -> Result<(), PlayerError> {
+        Ok(())
+    }
+}
+
+enum FileWriter<T: WavSample> {
+    Wav(WavWriter<T, BufWriter<File>>),
+    Raw(BufWriter<File>),
+}
+
+struct FileOutput<T: WavSample> {
+    // Taken when the file is finished
+    writer: Option<FileWriter<T>>,
+    channels: u16,
+    frames: Arc<AtomicU64>,
+}
+
+impl<T: WavSample + Send + 'static> PullOutput<T> for FileOutput<T> {
+    fn write(&mut self, samples: &[T]) -> Result<(), PlayerError> {
+        match self.writer.as_mut() {
+            Some(FileWriter::Wav(writer)) => writer.write_samples(samples)?,
+            Some(FileWriter::Raw(writer)) => {
+                for sample in samples {
+                    sample.write_le(writer)?;
+                }
+            }
+            None => return Ok(()),
+        }
+        self.frames.fetch_add((samples.len() / self.channels as usize) as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), PlayerError> {
+        match self.writer.take() {
+            Some(FileWriter::Wav(writer)) => {
+                writer.finish()?;
+            }
+            Some(FileWriter::Raw(mut writer)) => writer.flush()?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+// Runs a sink's callback on a thread of its own, for sinks that aren't a sound device
+struct PullThread {
+    control: Arc<PullControl>,
+    handle: JoinHandle<Result<(), PlayerError>>,
+}
+
+#[derive(Default)]
+struct PullControl {
+    playing: AtomicBool,
+    stopped: AtomicBool,
+    wakeup: Wakeup,
+}
+
+impl PullThread {
+    fn spawn<T, O>(
+        mut callback: SampleCallback<T>,
+        mut output: O,
+        config: SinkConfig,
+        realtime: bool,
+        mut on_error: ErrorCallback,
+    ) -> PullThread
+    where
+        T: Sample + Send + 'static,
+        O: PullOutput<T>,
+    {
+        let control = Arc::new(PullControl::default());
+        let thread_control = control.clone();
+
+        let handle = thread::spawn(move || {
+            let control = thread_control;
+
+            // 10ms at a time
+            let frames = (config.sample_rate / 100).max(1);
+            let mut buffer: Vec<T> = vec![Sample::from(&0.0); frames as usize * config.channels as usize];
+            let period = Duration::from_secs(1) * frames / config.sample_rate.max(1);
+            let mut next = Instant::now();
+
+            loop {
+                if !control.playing.load(Ordering::SeqCst) {
+                    control.wakeup.wait_until(|| {
+                        control.playing.load(Ordering::SeqCst) || control.stopped.load(Ordering::SeqCst)
+                    });
+                    next = Instant::now();
+                }
+                if control.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let written = callback(&mut buffer, Duration::from_secs(0));
+                if let Err(e) = output.write(&buffer[..written]) {
+                    on_error(e);
+                    return Ok(());
+                }
+
+                // Keep to the pace the audio would play at, without trying to catch up after falling behind
+                if realtime {
+                    next += period;
+                    let now = Instant::now();
+                    if next > now {
+                        thread::sleep(next - now);
+                    } else {
+                        next = now;
+                    }
+                }
+            }
+
+            output.finish()
+        });
+
+        PullThread { control, handle }
+    }
+
+    fn set_playing(&self, playing: bool) {
+        self.control.playing.store(playing, Ordering::SeqCst);
+        self.control.wakeup.wake();
+    }
+
+    // The callback mustn't be blocked waiting for audio when this is called, or it'll wait forever
+    fn stop(self) -> Result<(), PlayerError> {
+        self.control.stopped.store(true, Ordering::SeqCst);
+        self.control.wakeup.wake();
+        self.handle.join().map_err(|_| PlayerError::SinkThread)?
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use cpal::Sample;
+
+/// Software volume, shared between the player and the output callback.
+///
+/// Stored as atomics so the callback can read it without locking.
+pub struct Volume {
+    // Linear gain as f32 bits
+    gain: AtomicU32,
+    muted: AtomicBool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            muted: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Volume {
+    /// The linear gain (1.0 is unchanged), ignoring mute.
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        gain_to_db(self.gain())
+    }
+
+    pub fn set_gain_db(&self, db: f32) {
+        self.set_gain(db_to_gain(db));
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    // The gain the output should be heading towards
+    fn target(&self) -> f32 {
+        if self.is_muted() {
+            0.0
+        } else {
+            self.gain()
+        }
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// The quietest volume `gain_to_db` reports, which is where 16-bit audio runs out of bits.
+pub const MIN_DB: f32 = -96.0;
+
+/// Silence (a gain of 0) comes out as `MIN_DB` rather than minus infinity, so stepping the volume
+/// up from there in decibels works.
+pub fn gain_to_db(gain: f32) -> f32 {
+    (20.0 * gain.log10()).max(MIN_DB)
+}
+
+// Applies the volume in the output callback. Changes are ramped over RAMP_SECONDS
+// instead of jumping straight there, which would click ("zipper noise").
+pub(crate) struct GainRamp {
+    current: f32,
+    // Largest change allowed per frame
+    step: f32,
+}
+
+const RAMP_SECONDS: f32 = 0.02;
+
+impl GainRamp {
+    pub(crate) fn new(sample_rate: u32) -> GainRamp {
+        GainRamp {
+            current: 1.0,
+            step: 1.0 / (RAMP_SECONDS * sample_rate as f32),
+        }
+    }
+
+    pub(crate) fn apply<T: Sample>(&mut self, data: &mut [T], channels: usize, volume: &Volume) {
+        let target = volume.target();
+
+        // Nothing to do at unity gain
+        if self.current == target && target == 1.0 {
+            return;
+        }
+
+        for frame in data.chunks_mut(channels) {
+            if self.current < target {
+                self.current = (self.current + self.step).min(target);
+            } else if self.current > target {
+                self.current = (self.current - self.step).max(target);
+            }
+
+            for sample in frame {
+                *sample = T::from(&(sample.to_f32() * self.current));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn decibels_and_gain_round_trip() {
+        assert_eq!(db_to_gain(0.0), 1.0);
+        assert!(close(db_to_gain(-6.0), 0.5012));
+        assert!(close(db_to_gain(20.0), 10.0));
+        for db in [-60.0, -20.0, -6.0, -0.5, 0.0, 3.0, 12.0].iter() {
+            assert!(close(gain_to_db(db_to_gain(*db)), *db), "{} dB", db);
+        }
+    }
+
+    #[test]
+    fn silence_is_min_db() {
+        assert_eq!(gain_to_db(0.0), MIN_DB);
+        assert_eq!(gain_to_db(1e-9), MIN_DB);
+
+        let volume = Volume::default();
+        volume.set_gain(-1.0);
+        assert_eq!(volume.gain(), 0.0);
+        assert_eq!(volume.gain_db(), MIN_DB);
+    }
+
+    #[test]
+    fn ramps_one_step_per_frame() {
+        // 20ms at 1000 Hz is 20 frames, so each frame moves 0.05
+        let mut ramp = GainRamp::new(1000);
+        let volume = Volume::default();
+        volume.set_gain(0.5);
+
+        let mut data = [1.0f32; 8];
+        ramp.apply(&mut data, 2, &volume);
+        let expected = [0.95, 0.95, 0.9, 0.9, 0.85, 0.85, 0.8, 0.8];
+        assert!(data.iter().zip(expected.iter()).all(|(a, b)| close(*a, *b)), "{:?}", data);
+    }
+
+    #[test]
+    fn ramp_stops_at_the_target() {
+        let mut ramp = GainRamp::new(1000);
+        let volume = Volume::default();
+        volume.set_muted(true);
+
+        let mut data = [1.0f32; 30];
+        ramp.apply(&mut data, 1, &volume);
+        assert!(close(data[0], 0.95));
+        assert!(data[20..].iter().all(|sample| *sample == 0.0));
+
+        // And back up again once unmuted
+        volume.set_muted(false);
+        let mut data = [1.0f32; 30];
+        ramp.apply(&mut data, 1, &volume);
+        assert!(close(data[0], 0.05));
+        assert!(data[20..].iter().all(|sample| *sample == 1.0));
+    }
+
+    #[test]
+    fn unity_gain_leaves_samples_alone() {
+        let mut ramp = GainRamp::new(48000);
+        let mut data = [0.25f32, -0.5, 0.75, -1.0];
+        ramp.apply(&mut data, 2, &Volume::default());
+        assert_eq!(data, [0.25, -0.5, 0.75, -1.0]);
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// A sample type that can be stored in a WAV file.
+pub trait WavSample: Copy {
+    const FORMAT_TAG: u16;
+
+    fn write_le<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+}
+
+impl WavSample for f32 {
+    const FORMAT_TAG: u16 = WAVE_FORMAT_IEEE_FLOAT;
+
+    fn write_le<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl WavSample for i16 {
+    const FORMAT_TAG: u16 = WAVE_FORMAT_PCM;
+
+    fn write_le<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+/// Writes interleaved samples to a WAV file.
+///
+/// The header is written up front with the sizes left at zero, and filled in by `finish`.
+pub struct WavWriter<T, W: Write + Seek> {
+    writer: W,
+    data_bytes: u64,
+    // Where the fact chunk's sample count goes, for float files
+    fact_offset: Option<u64>,
+    channels: u16,
+    _sample: PhantomData<T>,
+}
+
+impl<T: WavSample> WavWriter<T, BufWriter<File>> {
+    /// Create (or overwrite) a WAV file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), channels, sample_rate)
+    }
+}
+
+impl<T: WavSample, W: Write + Seek> WavWriter<T, W> {
+    pub fn new(mut writer: W, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let bytes_per_sample = std::mem::size_of::<T>() as u16;
+        let block_align = channels * bytes_per_sample;
+        let is_float = T::FORMAT_TAG == WAVE_FORMAT_IEEE_FLOAT;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        // Non-PCM formats need the (empty) extension size on the end of the fmt chunk
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&(if is_float { 18u32 } else { 16u32 }).to_le_bytes())?;
+        writer.write_all(&T::FORMAT_TAG.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+
+        // ...and a fact chunk with the number of sample frames
+        let mut fact_offset = None;
+        if is_float {
+            writer.write_all(&0u16.to_le_bytes())?;
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            fact_offset = Some(writer.stream_position()?);
+            writer.write_all(&0u32.to_le_bytes())?;
+        }
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            data_bytes: 0,
+            fact_offset,
+            channels,
+            _sample: PhantomData,
+        })
+    }
+
+    /// Append interleaved samples.
+    pub fn write_samples(&mut self, samples: &[T]) -> io::Result<()> {
+        for sample in samples {
+            sample.write_le(&mut self.writer)?;
+        }
+        self.data_bytes += (samples.len() * std::mem::size_of::<T>()) as u64;
+        Ok(())
+    }
+
+    /// How many sample frames (one sample for every channel) have been written.
+    pub fn frames(&self) -> u64 {
+        self.data_bytes / (std::mem::size_of::<T>() as u64 * u64::from(self.channels))
+    }
+
+    /// Fill in the header sizes and hand back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // WAV sizes are 32 bits. Past 4GB the best we can do is max them out, which most
+        // readers take to mean "until the end of the file".
+        let end = self.writer.stream_position()?;
+        let header_bytes = end - self.data_bytes;
+        let riff_bytes = (end - 8).min(u64::from(u32::MAX)) as u32;
+        let data_bytes = self.data_bytes.min(u64::from(u32::MAX)) as u32;
+
+        if let Some(fact_offset) = self.fact_offset {
+            let frames = self.frames().min(u64::from(u32::MAX)) as u32;
+            self.writer.seek(SeekFrom::Start(fact_offset))?;
+            self.writer.write_all(&frames.to_le_bytes())?;
+        }
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&riff_bytes.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(header_bytes - 4))?;
+        self.writer.write_all(&data_bytes.to_le_bytes())?;
+
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn pcm_header_has_the_sizes_filled_in() {
+        let mut wav = WavWriter::<i16, _>::new(Cursor::new(Vec::new()), 2, 44100).unwrap();
+        wav.write_samples(&[1, -1, 2, -2, 3, -3]).unwrap();
+        assert_eq!(wav.frames(), 3);
+        let bytes = wav.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_PCM);
+        assert_eq!(u16_at(&bytes, 22), 2);
+        assert_eq!(u32_at(&bytes, 24), 44100);
+        assert_eq!(u32_at(&bytes, 28), 44100 * 4);
+        assert_eq!(u16_at(&bytes, 32), 4);
+        assert_eq!(u16_at(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+        assert_eq!(u16_at(&bytes, 44), 1);
+        assert_eq!(u16_at(&bytes, 46) as i16, -1);
+    }
+
+    #[test]
+    fn float_header_has_a_fact_chunk() {
+        let mut wav = WavWriter::<f32, _>::new(Cursor::new(Vec::new()), 1, 48000).unwrap();
+        wav.write_samples(&[0.0, 0.5, -0.5, 1.0, -1.0]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+
+        // 12 (RIFF) + 26 (fmt with its extension size) + 12 (fact) + 8 (data header)
+        assert_eq!(bytes.len(), 58 + 20);
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(u32_at(&bytes, 16), 18);
+        assert_eq!(u16_at(&bytes, 20), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(u16_at(&bytes, 34), 32);
+        assert_eq!(&bytes[38..42], b"fact");
+        assert_eq!(u32_at(&bytes, 46), 5);
+        assert_eq!(&bytes[50..54], b"data");
+        assert_eq!(u32_at(&bytes, 54), 20);
+    }
+}
@@ -0,0 +1,69 @@
+// Helpers shared by the integration tests. Each test binary uses a different subset of them.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+
+use ffmpeg_cpal_play_audio::WavWriter;
+
+/// A path in the temp directory that's unique to this test process and `name`.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ffmpeg-cpal-play-audio-{}-{}", std::process::id(), name))
+}
+
+/// Write a 16-bit WAV file holding `frames` frames of a quiet tone.
+pub fn write_tone(path: &PathBuf, frames: usize, channels: u16, sample_rate: u32) {
+    let mut wav = WavWriter::<i16, _>::create(path, channels, sample_rate).unwrap();
+    let samples: Vec<i16> = (0..frames)
+        .flat_map(|frame| {
+            let sample = ((frame as f32 * 0.05).sin() * 8000.0) as i16;
+            std::iter::repeat(sample).take(channels as usize)
+        })
+        .collect();
+    wav.write_samples(&samples).unwrap();
+    wav.finish().unwrap();
+}
+
+/// What a WAV file's header says: its format tag, channels, sample rate, bits per sample and data size.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WavHeader {
+    pub format_tag: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits: u16,
+    pub data_bytes: u32,
+}
+
+/// Read back the header of a WAV file, checking the RIFF size against the file's length.
+pub fn read_wav_header(path: &PathBuf) -> WavHeader {
+    let bytes = fs::read(path).unwrap();
+    let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let u32_at = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+    assert_eq!(&bytes[8..12], b"WAVE");
+
+    let mut header = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let size = u32_at(offset + 4) as usize;
+        match &bytes[offset..offset + 4] {
+            b"fmt " => header = Some((u16_at(offset + 8), u16_at(offset + 10), u32_at(offset + 12), u16_at(offset + 22))),
+            b"data" => {
+                let (format_tag, channels, sample_rate, bits) = header.expect("fmt chunk before data");
+                assert_eq!(offset + 8 + size, bytes.len());
+                return WavHeader {
+                    format_tag,
+                    channels,
+                    sample_rate,
+                    bits,
+                    data_bytes: size as u32,
+                };
+            }
+            _ => {}
+        }
+        offset += 8 + size;
+    }
+    panic!("no data chunk in {}", path.display());
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ffmpeg_cpal_play_audio::{render_to_wav, NullSink, PlaybackState, Player, PlayerOptions, PlaylistEntry, RenderOptions};
+
+use common::{read_wav_header, temp_path, write_tone};
+
+// Bytes before the samples in the files write_tone makes
+const WAV_HEADER: usize = 44;
+
+// What goes wrong with the first response the server sends
+#[derive(Clone, Copy)]
+enum Fault {
+    // Cut it off after this many bytes, and serve normally from then on
+    Drop(usize),
+    // Cut it off after this many bytes, then turn every connection away for a while. Long enough
+    // and ffmpeg gives up reconnecting by itself.
+    Outage(usize, Duration),
+    // Stop sending for a while after this many bytes, then carry on
+    Stall(usize, Duration),
+}
+
+struct Server {
+    url: String,
+    // Connections turned away during an outage
+    turned_away: Arc<AtomicUsize>,
+}
+
+// Serve `body` over HTTP on a local port, with Range support so ffmpeg can pick up where it left off
+fn serve(body: Vec<u8>, fault: Option<Fault>) -> Server {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/tone.wav", listener.local_addr().unwrap());
+    let body = Arc::new(body);
+    let faulted = Arc::new(AtomicBool::new(fault.is_none()));
+    let down_until = Arc::new(Mutex::new(None::<Instant>));
+    let turned_away = Arc::new(AtomicUsize::new(0));
+
+    let counter = turned_away.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            // Closing straight away, without reading the request, fails the same way a refused connection does
+            if down_until.lock().unwrap().is_some_and(|until| Instant::now() < until) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+
+            let (body, down_until) = (body.clone(), down_until.clone());
+            let fault = if faulted.swap(true, Ordering::SeqCst) { None } else { fault };
+            // ffmpeg can have more than one connection open, so each gets its own thread
+            thread::spawn(move || {
+                let _ = respond(&mut stream, &body, fault);
+                // The outage has to start before the connection closes, or ffmpeg's first try could slip in
+                if let Some(Fault::Outage(_, length)) = fault {
+                    *down_until.lock().unwrap() = Some(Instant::now() + length);
+                }
+            });
+        }
+    });
+    Server { url, turned_away }
+}
+
+fn respond(stream: &mut TcpStream, body: &[u8], fault: Option<Fault>) -> std::io::Result<()> {
+    // Only the Range header matters
+    let mut start = 0;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let lower = line.to_ascii_lowercase();
+        if let Some(range) = lower.strip_prefix("range: bytes=") {
+            start = range.trim().trim_end_matches('-').split('-').next().unwrap().parse().unwrap_or(0);
+        }
+    }
+    let start = start.min(body.len());
+
+    let status = if start > 0 { "206 Partial Content" } else { "200 OK" };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: audio/wav\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n\
+         Content-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len() - start,
+        start,
+        body.len().saturating_sub(1),
+        body.len()
+    )?;
+
+    let (cut, stall) = match fault {
+        Some(Fault::Drop(at)) | Some(Fault::Outage(at, _)) => (at, None),
+        Some(Fault::Stall(at, pause)) => (body.len(), Some((at, pause))),
+        None => (body.len(), None),
+    };
+    let end = cut.clamp(start, body.len());
+    let mut from = start;
+    if let Some((at, pause)) = stall {
+        let at = at.clamp(start, end);
+        stream.write_all(&body[from..at])?;
+        stream.flush()?;
+        thread::sleep(pause);
+        from = at;
+    }
+    stream.write_all(&body[from..end])?;
+    stream.flush()
+}
+
+fn tone_wav(name: &str, frames: usize) -> Vec<u8> {
+    let path = temp_path(name);
+    write_tone(&path, frames, 2, 44100);
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    bytes
+}
+
+#[test]
+fn plays_a_file_over_http_to_the_end() {
+    let server = serve(tone_wav("http-play.wav", 44100), None);
+
+    let sink = Box::new(NullSink::fast());
+    let mut player = Player::open_with_sink(Some(PlaylistEntry::new(server.url)), sink, PlayerOptions::default()).unwrap();
+    player.play().unwrap();
+    player.wait().unwrap();
+
+    let status = player.status();
+    assert_eq!(status.state, PlaybackState::Finished);
+    assert_eq!(status.duration.map(|duration| duration.as_millis()), Some(1000));
+}
+
+#[test]
+fn carries_on_after_the_connection_drops() {
+    // Two seconds, cut off about half way through the first time it's served
+    let body = tone_wav("http-drop.wav", 88200);
+    let cut = body.len() / 2;
+    let server = serve(body, Some(Fault::Drop(cut)));
+
+    let output = temp_path("http-drop-output.wav");
+    let (frames, errors) = render_to_wav(Some(PlaylistEntry::new(server.url)), &output, RenderOptions::default()).unwrap();
+    let header = read_wav_header(&output);
+    fs::remove_file(&output).unwrap();
+
+    // Every frame made it, none twice
+    assert_eq!(frames, 88200);
+    assert_eq!(errors.bad_packets, 0);
+    assert_eq!(header.data_bytes, 88200 * 4);
+}
+
+#[test]
+fn reconnects_after_ffmpeg_gives_up() {
+    // ffmpeg retries after 0, 1 and 3 seconds and then gives up, so a six second outage outlasts it.
+    // Everything after that is down to the decoder opening the stream again by itself.
+    let body = tone_wav("http-outage.wav", 88200);
+    let cut = body.len() / 2;
+    let server = serve(body, Some(Fault::Outage(cut, Duration::from_secs(6))));
+
+    let output = temp_path("http-outage-output.wav");
+    let (frames, errors) = render_to_wav(Some(PlaylistEntry::new(server.url)), &output, RenderOptions::default()).unwrap();
+    let header = read_wav_header(&output);
+    fs::remove_file(&output).unwrap();
+
+    assert!(server.turned_away.load(Ordering::SeqCst) > 0);
+    assert_eq!(frames, 88200);
+    assert_eq!(errors.bad_packets, 0);
+    assert_eq!(header.data_bytes, 88200 * 4);
+}
+
+#[test]
+fn waits_for_the_prebuffer_before_playing() {
+    // A second of audio, held up for a second after the first fifth of it
+    let body = tone_wav("http-prebuffer.wav", 44100);
+    let server = serve(body, Some(Fault::Stall(WAV_HEADER + 44100 / 5 * 4, Duration::from_secs(1))));
+
+    let options = PlayerOptions {
+        prebuffer_ms: 500,
+        ..PlayerOptions::default()
+    };
+    let sink = Box::new(NullSink::realtime());
+    let mut player = Player::open_with_sink(Some(PlaylistEntry::new(server.url)), sink, options).unwrap();
+    player.play().unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    // A fifth of a second isn't enough to start on
+    let status = player.status();
+    assert_eq!(status.state, PlaybackState::Buffering);
+    assert_eq!(status.position, Duration::from_secs(0));
+
+    player.wait().unwrap();
+    assert_eq!(player.status().state, PlaybackState::Finished);
+}
+
+#[test]
+fn rebuffers_after_running_dry() {
+    // Three seconds of audio, held up for a second and a half after the first
+    let body = tone_wav("http-rebuffer.wav", 44100 * 3);
+    let server = serve(body, Some(Fault::Stall(WAV_HEADER + 44100 * 4, Duration::from_millis(1500))));
+
+    let options = PlayerOptions {
+        prebuffer_ms: 300,
+        ..PlayerOptions::default()
+    };
+    let sink = Box::new(NullSink::realtime());
+    let mut player = Player::open_with_sink(Some(PlaylistEntry::new(server.url)), sink, options).unwrap();
+    player.play().unwrap();
+
+    // Every change of state, until it finishes
+    let mut states = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while Instant::now() < deadline {
+        let state = player.status().state;
+        if states.last() != Some(&state) {
+            states.push(state);
+        }
+        if state == PlaybackState::Finished {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    player.wait().unwrap();
+
+    // It runs dry while the server holds back, then picks up again once the buffer has refilled
+    let rebuffered = [PlaybackState::Playing, PlaybackState::Buffering, PlaybackState::Playing];
+    assert!(states.windows(3).any(|window| window == rebuffered), "{:?}", states);
+    assert_eq!(states.last(), Some(&PlaybackState::Finished));
+}
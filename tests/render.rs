@@ -0,0 +1,51 @@
+mod common;
+
+use std::fs;
+
+use ffmpeg_cpal_play_audio::{render_to_wav, PlaylistEntry, RenderOptions};
+
+use common::{read_wav_header, temp_path, write_tone};
+
+#[test]
+fn renders_a_wav_file_to_the_same_format() {
+    let input = temp_path("render-input.wav");
+    let output = temp_path("render-output.wav");
+    write_tone(&input, 22050, 2, 44100);
+
+    let (frames, errors) = render_to_wav(Some(PlaylistEntry::new(&input)), &output, RenderOptions::default()).unwrap();
+    let header = read_wav_header(&output);
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+
+    assert_eq!(frames, 22050);
+    assert_eq!(errors.bad_packets, 0);
+    assert_eq!((header.format_tag, header.channels, header.sample_rate, header.bits), (1, 2, 44100, 16));
+    assert_eq!(header.data_bytes, 22050 * 2 * 2);
+}
+
+#[test]
+fn renders_back_to_back_files_resampled_and_mixed() {
+    let first = temp_path("render-first.wav");
+    let second = temp_path("render-second.wav");
+    let output = temp_path("render-mixed.wav");
+    write_tone(&first, 4410, 1, 44100);
+    write_tone(&second, 4800, 2, 48000);
+
+    let options = RenderOptions {
+        sample_rate: Some(48000),
+        channels: Some(2),
+        sample_format: cpal::SampleFormat::F32,
+        ..RenderOptions::default()
+    };
+    let entries = vec![PlaylistEntry::new(&first), PlaylistEntry::new(&second)];
+    let (frames, _) = render_to_wav(entries, &output, options).unwrap();
+    let header = read_wav_header(&output);
+    for path in [&first, &second, &output] {
+        fs::remove_file(path).unwrap();
+    }
+
+    // A tenth of a second each, give or take the resampler's rounding
+    assert!((9590..=9610).contains(&frames), "{} frames", frames);
+    assert_eq!((header.format_tag, header.channels, header.sample_rate, header.bits), (3, 2, 48000, 32));
+    assert_eq!(u64::from(header.data_bytes), frames * 2 * 4);
+}
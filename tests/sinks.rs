@@ -0,0 +1,81 @@
+mod common;
+
+use std::fs;
+use std::time::Duration;
+
+use ffmpeg_cpal_play_audio::{
+    AudioSink, FileFormat, FileSink, NullSink, PlaybackState, Player, PlayerError, PlayerOptions, PlaylistEntry, SinkCallback,
+};
+
+use common::{temp_path, write_tone};
+
+#[test]
+fn file_sink_writes_raw_samples() {
+    let input = temp_path("sink-input.wav");
+    let output = temp_path("sink-output.raw");
+    write_tone(&input, 8000, 2, 16000);
+
+    let sink = FileSink::new(&output, FileFormat::Raw);
+    let mut player = Player::open_with_sink(Some(PlaylistEntry::new(&input)), Box::new(sink), PlayerOptions::default()).unwrap();
+    player.play().unwrap();
+    player.wait().unwrap();
+
+    let bytes = fs::read(&output).unwrap();
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+
+    // 16-bit stereo with no header, so every frame is 4 bytes
+    assert_eq!(bytes.len(), 8000 * 4);
+    assert_eq!(player.status().state, PlaybackState::Finished);
+}
+
+#[test]
+fn null_sink_plays_to_the_end() {
+    let input = temp_path("sink-null.wav");
+    write_tone(&input, 44100, 2, 44100);
+
+    let mut player = Player::open_with_sink(Some(PlaylistEntry::new(&input)), Box::new(NullSink::fast()), PlayerOptions::default()).unwrap();
+    player.play().unwrap();
+    player.wait().unwrap();
+    fs::remove_file(&input).unwrap();
+
+    let status = player.status();
+    assert_eq!(status.state, PlaybackState::Finished);
+    assert_eq!(status.duration.map(|duration| duration.as_millis()), Some(1000));
+}
+
+#[test]
+fn null_sink_can_stop_part_way_through() {
+    let input = temp_path("sink-stop.wav");
+    write_tone(&input, 44100 * 10, 1, 44100);
+
+    // In real time, so there's plenty of time to stop it before the end
+    let mut player = Player::open_with_sink(Some(PlaylistEntry::new(&input)), Box::new(NullSink::realtime()), PlayerOptions::default()).unwrap();
+    player.play().unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    player.stop().unwrap();
+    fs::remove_file(&input).unwrap();
+
+    assert_eq!(player.status().state, PlaybackState::Stopped);
+}
+
+#[test]
+fn sinks_have_to_be_configured_before_they_start() {
+    let output = temp_path("sink-unconfigured.wav");
+    let sinks: Vec<Box<dyn AudioSink>> = vec![Box::new(NullSink::fast()), Box::new(FileSink::new(&output, FileFormat::Wav))];
+
+    for mut sink in sinks {
+        // Nothing has started, so there's nothing to play or pause
+        assert!(sink.play().is_ok());
+        assert!(sink.pause().is_ok());
+
+        let callback = SinkCallback::F32(Box::new(|_: &mut [f32], _| 0));
+        match sink.start(callback, Box::new(|_| {})) {
+            Err(PlayerError::SinkNotConfigured) => {}
+            other => panic!("expected SinkNotConfigured, got {:?}", other),
+        }
+        assert!(sink.stop().is_ok());
+    }
+    // Not configured, so the file sink never created it
+    assert!(!output.exists());
+}